@@ -1,70 +1,410 @@
+use std::fs;
 use std::path::Path;
 
 use async_openai::error::OpenAIError;
 use async_openai::types::{
+    AudioInput, AudioResponseFormat, ChatCompletionRequestAssistantMessageArgs,
     ChatCompletionRequestMessage, ChatCompletionRequestMessageContentPartImageArgs,
-    ChatCompletionRequestMessageContentPartTextArgs, ChatCompletionRequestUserMessageArgs,
-    ChatCompletionRequestUserMessageContent, ChatCompletionRequestUserMessageContentPart,
-    CreateChatCompletionRequestArgs, CreateSpeechRequestArgs, ImageUrlArgs, SpeechModel, Voice,
+    ChatCompletionRequestMessageContentPartTextArgs, ChatCompletionRequestSystemMessageArgs,
+    ChatCompletionRequestUserMessageArgs, ChatCompletionRequestUserMessageContent,
+    ChatCompletionRequestUserMessageContentPart, CreateChatCompletionRequest,
+    CreateChatCompletionRequestArgs, CreateSpeechRequestArgs, CreateTranscriptionRequestArgs,
+    ImageUrlArgs, ResponseFormat, SpeechModel, TimestampGranularity, Voice,
 };
 use async_openai::Client;
+use serde::{Deserialize, Serialize};
 
-pub(crate) async fn annotation_frames(prompt: &str, frames: Vec<String>) -> anyhow::Result<String> {
+use crate::cache::Cache;
+use crate::prompt::Prompt;
+use crate::transcript::TranscriptSegment;
+use crate::video;
+
+fn frame_messages(
+    system: Option<&str>,
+    text: &str,
+    frames: Vec<ChatCompletionRequestUserMessageContentPart>,
+) -> anyhow::Result<Vec<ChatCompletionRequestMessage>> {
+    let mut messages = Vec::new();
+    if let Some(system) = system {
+        messages.push(ChatCompletionRequestMessage::System(
+            ChatCompletionRequestSystemMessageArgs::default()
+                .content(system)
+                .build()?,
+        ));
+    }
+    messages.push(ChatCompletionRequestMessage::User(
+        ChatCompletionRequestUserMessageArgs::default()
+            .content(ChatCompletionRequestUserMessageContent::Array(
+                [vec![text_part(text)?], frames].concat(),
+            ))
+            .build()?,
+    ));
+    Ok(messages)
+}
+
+#[derive(Clone, clap::Args)]
+pub(crate) struct Settings {
+    /// Chat model used to write, shorten and translate the commentary
+    #[arg(long, default_value = "gpt-4o")]
+    pub(crate) model: String,
+    #[arg(long, default_value = "nova")]
+    pub(crate) voice: String,
+    #[arg(long, default_value = "tts-1-hd")]
+    pub(crate) speech_model: String,
+    #[arg(long, default_value = "whisper-1")]
+    pub(crate) transcription_model: String,
+    #[arg(long, default_value_t = 300)]
+    pub(crate) chat_timeout_sec: u64,
+    #[arg(long, default_value_t = 120)]
+    pub(crate) speech_timeout_sec: u64,
+    /// Transcriptions take longer than chats for long audio
+    #[arg(long, default_value_t = 600)]
+    pub(crate) transcription_timeout_sec: u64,
+}
+
+pub(crate) struct Refinement {
+    pub(crate) comments: Vec<String>,
+    pub(crate) feedback: String,
+}
+
+fn refinement_messages(
+    refinements: &[Refinement],
+    reply: impl Fn(&[String]) -> String,
+    reminder: &str,
+) -> anyhow::Result<Vec<ChatCompletionRequestMessage>> {
+    let mut messages = Vec::new();
+    for refinement in refinements {
+        messages.push(ChatCompletionRequestMessage::Assistant(
+            ChatCompletionRequestAssistantMessageArgs::default()
+                .content(reply(&refinement.comments))
+                .build()?,
+        ));
+        messages.push(ChatCompletionRequestMessage::User(
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(format!("{}{}", refinement.feedback, reminder))
+                .build()?,
+        ));
+    }
+    Ok(messages)
+}
+
+fn text_part(text: &str) -> Result<ChatCompletionRequestUserMessageContentPart, OpenAIError> {
+    Ok(ChatCompletionRequestUserMessageContentPart::Text(
+        ChatCompletionRequestMessageContentPartTextArgs::default()
+            .text(text)
+            .build()?,
+    ))
+}
+
+fn image_part(frame: String) -> Result<ChatCompletionRequestUserMessageContentPart, OpenAIError> {
+    Ok(ChatCompletionRequestUserMessageContentPart::ImageUrl(
+        ChatCompletionRequestMessageContentPartImageArgs::default()
+            .image_url(ImageUrlArgs::default().url(frame).build()?)
+            .build()?,
+    ))
+}
+
+pub(crate) async fn annotation_frames(
+    prompt: &Prompt,
+    frames: Vec<String>,
+    refinements: &[Refinement],
+    settings: &Settings,
+    cache: &Cache,
+) -> anyhow::Result<String> {
+    let mut messages = frame_messages(
+        prompt.system.as_deref(),
+        &prompt.user,
+        frames
+            .into_iter()
+            .map(image_part)
+            .collect::<Result<_, _>>()?,
+    )?;
+    messages.extend(refinement_messages(
+        refinements,
+        |comments| comments.concat(),
+        "\n\nReply with the revised commentary only.",
+    )?);
+    let request = CreateChatCompletionRequestArgs::default()
+        .model(&settings.model)
+        .max_tokens(512_u32)
+        .messages(messages)
+        .build()?;
+
+    chat(request, settings, cache).await
+}
+
+#[derive(Serialize, Deserialize)]
+struct SegmentsResponse {
+    segments: Vec<String>,
+}
+
+pub(crate) async fn annotation_segments(
+    prompt: &Prompt,
+    frames: Vec<video::CapturedFrame>,
+    slots: &[video::Gap],
+    refinements: &[Refinement],
+    settings: &Settings,
+    cache: &Cache,
+) -> anyhow::Result<Vec<String>> {
+    let slot_list = slots
+        .iter()
+        .enumerate()
+        .map(|(index, slot)| {
+            format!(
+                "{}. from {:.1}s to {:.1}s ({:.1} seconds)",
+                index + 1,
+                slot.start_msec as f64 / 1000.0,
+                slot.end_msec as f64 / 1000.0,
+                (slot.end_msec - slot.start_msec) as f64 / 1000.0
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let text = format!(
+        "{}\n\nThe commentary can only be spoken in the following {} time slots, measured from the first frame. \
+         Write one piece of commentary for each slot about what is shown around that time, \
+         short enough to be spoken within the slot. \
+         Reply with a JSON object of the form {{\"segments\": [\"...\"]}} \
+         containing exactly {} strings in slot order.\n\n{}",
+        prompt.user,
+        slots.len(),
+        slots.len(),
+        slot_list
+    );
+
+    let mut parts = Vec::new();
+    for frame in frames {
+        parts.push(text_part(&format!(
+            "Frame at {:.1}s:",
+            frame.timestamp_msec as f64 / 1000.0
+        ))?);
+        parts.push(image_part(frame.base64)?);
+    }
+    let mut messages = frame_messages(prompt.system.as_deref(), &text, parts)?;
+    messages.extend(refinement_messages(
+        refinements,
+        |comments| {
+            serde_json::to_string(&SegmentsResponse {
+                segments: comments.to_vec(),
+            })
+            .unwrap_or_default()
+        },
+        &format!(
+            "\n\nReply with the revised JSON object containing exactly {} strings in slot order.",
+            slots.len()
+        ),
+    )?);
+    let request = CreateChatCompletionRequestArgs::default()
+        .model(&settings.model)
+        .max_tokens(2048_u32)
+        .response_format(ResponseFormat::JsonObject)
+        .messages(messages)
+        .build()?;
+
+    let response: SegmentsResponse = serde_json::from_str(&chat(request, settings, cache).await?)?;
+    if response.segments.len() != slots.len() {
+        anyhow::bail!(
+            "Expected {} segments from OpenAI, got {}",
+            slots.len(),
+            response.segments.len()
+        );
+    }
+    Ok(response.segments)
+}
+
+pub(crate) async fn shorten_comment(
+    comment: &str,
+    speech_msec: i64,
+    target_msec: i64,
+    settings: &Settings,
+    cache: &Cache,
+) -> anyhow::Result<String> {
+    let prompt = format!(
+        "The following commentary takes {:.1} seconds to read aloud, but it must fit in {:.1} seconds. \
+         Rewrite it to be shorter while keeping its language, tone and key points. \
+         Reply with the rewritten commentary only.\n\n{}",
+        speech_msec as f64 / 1000.0,
+        target_msec as f64 / 1000.0,
+        comment
+    );
     let request = CreateChatCompletionRequestArgs::default()
-        .model("gpt-4o")
+        .model(&settings.model)
         .max_tokens(512_u32)
         .messages([ChatCompletionRequestMessage::User(
             ChatCompletionRequestUserMessageArgs::default()
-                .content(ChatCompletionRequestUserMessageContent::Array(
-                    [
-                        vec![ChatCompletionRequestUserMessageContentPart::Text(
-                            ChatCompletionRequestMessageContentPartTextArgs::default()
-                                .text(prompt)
-                                .build()?,
-                        )],
-                        frames
-                            .into_iter()
-                            .map(|frame| -> Result<_, OpenAIError> {
-                                Ok(ChatCompletionRequestUserMessageContentPart::ImageUrl(
-                                    ChatCompletionRequestMessageContentPartImageArgs::default()
-                                        .image_url(ImageUrlArgs::default().url(frame).build()?)
-                                        .build()?,
-                                ))
-                            })
-                            .collect::<Result<_, _>>()?,
-                    ]
-                    .concat(),
-                ))
+                .content(prompt)
                 .build()?,
         )])
         .build()?;
 
+    chat(request, settings, cache).await
+}
+
+pub(crate) async fn translate_comment(
+    comment: &str,
+    language: &str,
+    settings: &Settings,
+    cache: &Cache,
+) -> anyhow::Result<String> {
+    let prompt = format!(
+        "Translate the following video commentary into {}. \
+         Keep its tone and about the same length so that it can be spoken in the same time. \
+         Reply with the translation only.\n\n{}",
+        language, comment
+    );
+    let request = CreateChatCompletionRequestArgs::default()
+        .model(&settings.model)
+        .max_tokens(512_u32)
+        .messages([ChatCompletionRequestMessage::User(
+            ChatCompletionRequestUserMessageArgs::default()
+                .content(prompt)
+                .build()?,
+        )])
+        .build()?;
+
+    chat(request, settings, cache).await
+}
+
+async fn chat(
+    request: CreateChatCompletionRequest,
+    settings: &Settings,
+    cache: &Cache,
+) -> anyhow::Result<String> {
+    let key = Cache::key(&request)?;
+    if let Some(content) = cache.get("chat", &key)? {
+        return Ok(String::from_utf8(content)?);
+    }
+
     let ai_client = Client::new();
     let response = tokio::time::timeout(
-        tokio::time::Duration::from_secs(300),
+        tokio::time::Duration::from_secs(settings.chat_timeout_sec),
         ai_client.chat().create(request),
     )
     .await??;
-    response.choices[0]
+    let content = response.choices[0]
         .clone()
         .message
         .content
-        .ok_or(anyhow::anyhow!("No content in response from OpenAI"))
+        .ok_or(anyhow::anyhow!("No content in response from OpenAI"))?;
+    cache.put("chat", &key, content.as_bytes())?;
+    Ok(content)
 }
 
-pub(crate) async fn audio_speech(text: &str, output_path: &Path) -> anyhow::Result<()> {
+pub(crate) async fn audio_speech(
+    text: &str,
+    output_path: &Path,
+    speed: f32,
+    settings: &Settings,
+    cache: &Cache,
+) -> anyhow::Result<()> {
     let request = CreateSpeechRequestArgs::default()
         .input(text)
-        .voice(Voice::Nova)
-        .model(SpeechModel::Tts1Hd)
+        .voice(
+            serde_json::from_value::<Voice>(serde_json::json!(settings.voice))
+                .map_err(|_| anyhow::anyhow!("Unknown voice: {}", settings.voice))?,
+        )
+        .model(serde_json::from_value::<SpeechModel>(serde_json::json!(
+            settings.speech_model
+        ))?)
+        .speed(speed)
         .build()?;
 
+    let key = Cache::key(&request)?;
+    if let Some(audio) = cache.get("speech", &key)? {
+        fs::write(output_path, audio)?;
+        return Ok(());
+    }
+
     let client = Client::new();
     let response = tokio::time::timeout(
-        tokio::time::Duration::from_secs(120),
+        tokio::time::Duration::from_secs(settings.speech_timeout_sec),
         client.audio().speech(request),
     )
     .await??;
     response.save(output_path).await?;
+    cache.put("speech", &key, &response.bytes)?;
     Ok(())
 }
+
+pub(crate) async fn transcribe(
+    audio_path: &Path,
+    settings: &Settings,
+    cache: &Cache,
+) -> anyhow::Result<Vec<TranscriptSegment>> {
+    let audio = fs::read(audio_path)?;
+    let key = Cache::key(&(&settings.transcription_model, Cache::digest(&audio)))?;
+    if let Some(segments) = cache.get("transcript", &key)? {
+        return Ok(serde_json::from_slice(&segments)?);
+    }
+
+    let file_name = audio_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    let request = CreateTranscriptionRequestArgs::default()
+        .file(AudioInput::from_vec_u8(file_name, audio))
+        .model(&settings.transcription_model)
+        .response_format(AudioResponseFormat::VerboseJson)
+        .timestamp_granularities(vec![TimestampGranularity::Segment])
+        .build()?;
+
+    let client = Client::new();
+    let response = tokio::time::timeout(
+        tokio::time::Duration::from_secs(settings.transcription_timeout_sec),
+        client.audio().transcribe_verbose_json(request),
+    )
+    .await??;
+    let segments: Vec<TranscriptSegment> = response
+        .segments
+        .unwrap_or_default()
+        .into_iter()
+        .map(|segment| TranscriptSegment {
+            start_msec: (segment.start * 1000.0) as i64,
+            end_msec: (segment.end * 1000.0) as i64,
+            text: segment.text,
+        })
+        .collect();
+    cache.put("transcript", &key, &serde_json::to_vec(&segments)?)?;
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        settings: Settings,
+    }
+
+    #[tokio::test]
+    async fn second_run_is_served_from_the_cache() {
+        let dir = std::env::temp_dir().join(format!("annotai-chat-cache-{}", std::process::id()));
+        let cache = Cache::new(&dir);
+        let settings = Cli::parse_from(["annotai"]).settings;
+        let request = || {
+            CreateChatCompletionRequestArgs::default()
+                .model(&settings.model)
+                .messages([ChatCompletionRequestMessage::User(
+                    ChatCompletionRequestUserMessageArgs::default()
+                        .content("Describe the clip")
+                        .build()
+                        .unwrap(),
+                )])
+                .build()
+                .unwrap()
+        };
+        // What the first run stored; the second one never gets as far as the API.
+        cache
+            .put("chat", &Cache::key(&request()).unwrap(), b"A cat naps.")
+            .unwrap();
+        assert_eq!(
+            chat(request(), &settings, &cache).await.unwrap(),
+            "A cat naps."
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,201 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// Numbers the temporary files of this process, so that concurrent writers never share one.
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) struct Cache {
+    dir: Option<PathBuf>,
+}
+
+impl Cache {
+    pub(crate) fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: Some(dir.into()),
+        }
+    }
+
+    pub(crate) fn disabled() -> Self {
+        Self { dir: None }
+    }
+
+    pub(crate) fn key(request: &impl Serialize) -> anyhow::Result<String> {
+        Ok(Self::digest(&serde_json::to_vec(request)?))
+    }
+
+    pub(crate) fn digest(data: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(data))
+    }
+
+    fn entry_path(&self, kind: &str, key: &str) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| dir.join(kind).join(key))
+    }
+
+    pub(crate) fn get(&self, kind: &str, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(path) = self.entry_path(kind, key) else {
+            return Ok(None);
+        };
+        if !fs::exists(&path)? {
+            return Ok(None);
+        }
+        // Refresh the entry so that pruning only removes unused ones.
+        fs::File::options()
+            .append(true)
+            .open(&path)?
+            .set_modified(SystemTime::now())?;
+        println!("Cache hit: {}/{}", kind, key);
+        Ok(Some(fs::read(&path)?))
+    }
+
+    pub(crate) fn put(&self, kind: &str, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        let Some(path) = self.entry_path(kind, key) else {
+            return Ok(());
+        };
+        let dir = path.parent().ok_or(anyhow::anyhow!("Invalid cache path"))?;
+        fs::create_dir_all(dir)?;
+        let tmp_path = path.with_extension(format!(
+            "{}.{}.tmp",
+            std::process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&tmp_path, data)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// Removes the entries unused for longer than `max_age`, then the least recently used ones
+    /// until the rest fit in `max_bytes`.
+    pub(crate) fn prune(&self, max_age: Duration, max_bytes: u64) -> anyhow::Result<usize> {
+        let Some(dir) = &self.dir else {
+            return Ok(0);
+        };
+        if !fs::exists(dir)? {
+            return Ok(0);
+        }
+        let now = SystemTime::now();
+        let mut removed = 0;
+        let mut kept = Vec::new();
+        for kind in fs::read_dir(dir)? {
+            let kind = kind?;
+            if !kind.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(kind.path())? {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                let modified = metadata.modified()?;
+                if now.duration_since(modified).unwrap_or_default() > max_age {
+                    fs::remove_file(entry.path())?;
+                    removed += 1;
+                } else {
+                    kept.push((modified, metadata.len(), entry.path()));
+                }
+            }
+        }
+
+        let mut total_bytes: u64 = kept.iter().map(|(_, len, _)| len).sum();
+        kept.sort_by_key(|(modified, _, _)| *modified);
+        for (_, len, path) in kept {
+            if total_bytes <= max_bytes {
+                break;
+            }
+            fs::remove_file(path)?;
+            total_bytes -= len;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn test_cache(name: &str) -> (Cache, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("annotai-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        (Cache::new(&dir), dir)
+    }
+
+    /// Ages an entry as if it was last used `age` ago.
+    fn age_entry(dir: &Path, kind: &str, key: &str, age: Duration) {
+        fs::File::options()
+            .append(true)
+            .open(dir.join(kind).join(key))
+            .unwrap()
+            .set_modified(SystemTime::now() - age)
+            .unwrap();
+    }
+
+    #[test]
+    fn keys_are_stable() {
+        let key = Cache::key(&("tts-1", "nova", "Hello")).unwrap();
+        assert_eq!(
+            key,
+            "e42a8a57b1c85e7da55fa68f3dd34c464d7bd98ca9bd2c546f20ff2c72e10201"
+        );
+        assert_eq!(Cache::key(&("tts-1", "nova", "Hello")).unwrap(), key);
+        assert_ne!(Cache::key(&("tts-1", "nova", "Hello!")).unwrap(), key);
+    }
+
+    #[test]
+    fn get_returns_what_was_put() {
+        let (cache, dir) = test_cache("cache-round-trip");
+        assert_eq!(cache.get("speech", "key").unwrap(), None);
+        cache.put("speech", "key", b"audio").unwrap();
+        assert_eq!(
+            cache.get("speech", "key").unwrap().as_deref(),
+            Some(&b"audio"[..])
+        );
+        assert_eq!(cache.get("chat", "key").unwrap(), None);
+        // Only the entry itself is left behind.
+        assert_eq!(fs::read_dir(dir.join("speech")).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn disabled_cache_keeps_nothing() {
+        let cache = Cache::disabled();
+        cache.put("chat", "key", b"comment").unwrap();
+        assert_eq!(cache.get("chat", "key").unwrap(), None);
+        assert_eq!(cache.prune(Duration::ZERO, 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn prune_removes_old_entries() {
+        let (cache, dir) = test_cache("cache-prune-age");
+        cache.put("chat", "old", b"comment").unwrap();
+        cache.put("chat", "new", b"comment").unwrap();
+        age_entry(&dir, "chat", "old", Duration::from_secs(3 * 24 * 60 * 60));
+        assert_eq!(
+            cache
+                .prune(Duration::from_secs(2 * 24 * 60 * 60), u64::MAX)
+                .unwrap(),
+            1
+        );
+        assert_eq!(cache.get("chat", "old").unwrap(), None);
+        assert!(cache.get("chat", "new").unwrap().is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prune_removes_least_recently_used_entries_over_the_size() {
+        let (cache, dir) = test_cache("cache-prune-size");
+        for (key, age_sec) in [("a", 30), ("b", 20), ("c", 10)] {
+            cache.put("speech", key, &[0; 100]).unwrap();
+            age_entry(&dir, "speech", key, Duration::from_secs(age_sec));
+        }
+        // Using an entry makes it the most recent one.
+        cache.get("speech", "a").unwrap();
+        assert_eq!(cache.prune(Duration::MAX, 200).unwrap(), 1);
+        assert_eq!(cache.get("speech", "b").unwrap(), None);
+        assert!(cache.get("speech", "a").unwrap().is_some());
+        assert!(cache.get("speech", "c").unwrap().is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,127 @@
+use std::fs;
+use std::path::Path;
+
+/// Longest chapter title taken from a comment, in characters.
+const TITLE_CHARS: usize = 48;
+
+#[derive(Clone, Debug)]
+pub(crate) struct Chapter {
+    pub(crate) start_msec: i64,
+    pub(crate) end_msec: i64,
+    pub(crate) title: String,
+}
+
+/// The first sentence of a comment, cut at a word boundary when it is too long for a title.
+fn title(comment: &str) -> String {
+    let sentence = comment
+        .split_inclusive(['.', '!', '?', '。', '！', '？'])
+        .next()
+        .unwrap_or_default()
+        .trim();
+    if sentence.chars().count() <= TITLE_CHARS {
+        return sentence.to_owned();
+    }
+    let cut: String = sentence.chars().take(TITLE_CHARS).collect();
+    let cut = match cut.rfind(' ') {
+        Some(space) if space > 0 => &cut[..space],
+        _ => &cut,
+    };
+    format!("{}…", cut.trim_end_matches([',', ';', ':', ' ']))
+}
+
+/// One chapter per commentary segment, each running until the next one starts, the first
+/// from the start of the video and the last to its end.
+pub(crate) fn from_segments(segments: &[(i64, &str)], duration_msec: i64) -> Vec<Chapter> {
+    segments
+        .iter()
+        .enumerate()
+        .map(|(index, (start_msec, comment))| Chapter {
+            start_msec: if index == 0 { 0 } else { *start_msec },
+            end_msec: segments
+                .get(index + 1)
+                .map_or(duration_msec, |(next_msec, _)| *next_msec),
+            title: title(comment),
+        })
+        .filter(|chapter| chapter.end_msec > chapter.start_msec)
+        .collect()
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Writes the chapters as an FFmpeg metadata file, which `ffmpeg -i video -i chapters.txt
+/// -map_chapters 1` and most chapter editors read.
+pub(crate) fn write_ffmetadata(path: &Path, chapters: &[Chapter]) -> anyhow::Result<()> {
+    let mut metadata = String::from(";FFMETADATA1\n");
+    for chapter in chapters {
+        metadata.push_str(&format!(
+            "\n[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle={}\n",
+            chapter.start_msec,
+            chapter.end_msec,
+            escape(&chapter.title)
+        ));
+    }
+    fs::write(path, metadata)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chapters_cover_the_video() {
+        let chapters = from_segments(
+            &[
+                (2000, "A cat walks in. It sits down."),
+                (10000, "The cat leaves!"),
+                (10000, "Nothing happens."),
+                (25000, "The end?"),
+            ],
+            30000,
+        );
+        let spans: Vec<_> = chapters
+            .iter()
+            .map(|chapter| (chapter.start_msec, chapter.end_msec, chapter.title.as_str()))
+            .collect();
+        assert_eq!(
+            spans,
+            [
+                (0, 10000, "A cat walks in."),
+                (10000, 25000, "Nothing happens."),
+                (25000, 30000, "The end?"),
+            ]
+        );
+        assert!(from_segments(&[], 30000).is_empty());
+    }
+
+    #[test]
+    fn long_titles_are_cut_at_a_word() {
+        let chapter = &from_segments(
+            &[(
+                0,
+                "A very long first sentence about everything, that goes on and on without a stop",
+            )],
+            1000,
+        )[0];
+        assert_eq!(
+            chapter.title,
+            "A very long first sentence about everything…"
+        );
+        assert_eq!(title("猫が歩いてくる。座る。"), "猫が歩いてくる。");
+    }
+
+    #[test]
+    fn metadata_values_are_escaped() {
+        assert_eq!(escape("Plain title"), "Plain title");
+        assert_eq!(escape("a=b; #c\\d\ne"), "a\\=b\\; \\#c\\\\d\\\ne");
+    }
+}
@@ -0,0 +1,367 @@
+use clap::Command;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+
+const FILE_NAME: &str = "annotai.toml";
+const ENV_PREFIX: &str = "ANNOTAI_";
+
+#[derive(Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    profiles: BTreeMap<String, toml::Table>,
+    #[serde(flatten)]
+    settings: toml::Table,
+}
+
+struct Setting {
+    value: toml::Value,
+    source: String,
+}
+
+/// Settings from the config files, applied as the defaults of the command line.
+///
+/// From lowest to highest precedence: built-in defaults, the config files
+/// (user config dir, then the current directory, or only `--config`), the
+/// selected profile, `ANNOTAI_*` environment variables and command-line flags.
+pub(crate) struct Config {
+    files: Vec<PathBuf>,
+    profile: Option<String>,
+    settings: BTreeMap<String, Setting>,
+}
+
+fn flag_value(args: &[OsString], name: &str) -> Option<OsString> {
+    let flag = format!("--{}", name);
+    let prefix = format!("--{}=", name);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        if arg == flag {
+            return args.next().cloned();
+        }
+        if let Some(value) = arg.strip_prefix(&prefix) {
+            return Some(value.into());
+        }
+    }
+    env::var_os(format!("{}{}", ENV_PREFIX, name.to_uppercase()))
+}
+
+fn user_config_path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(dir.join("annotai").join(FILE_NAME))
+}
+
+fn arg_values(key: &str, value: &toml::Value) -> anyhow::Result<Vec<String>> {
+    Ok(match value {
+        toml::Value::String(value) => vec![value.clone()],
+        toml::Value::Integer(value) => vec![value.to_string()],
+        toml::Value::Float(value) => vec![value.to_string()],
+        toml::Value::Boolean(value) => vec![value.to_string()],
+        toml::Value::Array(values) => values
+            .iter()
+            .map(|value| arg_values(key, value))
+            .collect::<anyhow::Result<Vec<_>>>()?
+            .concat(),
+        _ => anyhow::bail!("Unsupported value for {} in config", key),
+    })
+}
+
+fn display_value(value: &str) -> String {
+    if value.parse::<f64>().is_ok() || value.parse::<bool>().is_ok() {
+        value.to_owned()
+    } else {
+        toml::Value::String(value.to_owned()).to_string()
+    }
+}
+
+impl Config {
+    pub(crate) fn load(args: &[OsString]) -> anyhow::Result<Self> {
+        let files = match flag_value(args, "config") {
+            Some(path) => vec![PathBuf::from(path)],
+            None => [user_config_path(), Some(PathBuf::from(FILE_NAME))]
+                .into_iter()
+                .flatten()
+                .filter(|path| path.is_file())
+                .collect(),
+        };
+        let profile =
+            flag_value(args, "profile").map(|profile| profile.to_string_lossy().into_owned());
+
+        let mut settings = BTreeMap::new();
+        let mut profiles = Vec::new();
+        for path in &files {
+            let content = fs::read_to_string(path)
+                .map_err(|e| anyhow::anyhow!("Failed to read config {}: {}", path.display(), e))?;
+            let file: ConfigFile = toml::from_str(&content)
+                .map_err(|e| anyhow::anyhow!("Invalid config {}: {}", path.display(), e))?;
+            for (key, value) in file.settings {
+                let source = path.display().to_string();
+                settings.insert(key.replace('-', "_"), Setting { value, source });
+            }
+            profiles.push((path, file.profiles));
+        }
+
+        if let Some(profile) = &profile {
+            let mut found = false;
+            for (path, profiles) in profiles {
+                let Some(table) = profiles.get(profile) else {
+                    continue;
+                };
+                found = true;
+                for (key, value) in table {
+                    let source = format!("{} [profiles.{}]", path.display(), profile);
+                    settings.insert(
+                        key.replace('-', "_"),
+                        Setting {
+                            value: value.clone(),
+                            source,
+                        },
+                    );
+                }
+            }
+            if !found {
+                anyhow::bail!("Unknown config profile: {}", profile);
+            }
+        }
+
+        Ok(Self {
+            files,
+            profile,
+            settings,
+        })
+    }
+
+    /// Sets the config values and environment variable names on every flag of the command and its subcommands.
+    pub(crate) fn apply(&self, command: Command) -> anyhow::Result<Command> {
+        let mut unknown: BTreeSet<&String> = self.settings.keys().collect();
+        let command = self.set_defaults(command, &mut unknown)?;
+        if let Some(key) = unknown.first() {
+            anyhow::bail!(
+                "Unknown setting {} in {}",
+                key,
+                self.settings[key.as_str()].source
+            );
+        }
+        Ok(command)
+    }
+
+    /// Like `apply`, for a command that takes only some of the settings, such as the pipeline
+    /// options of a server job.
+    pub(crate) fn apply_known(&self, command: Command) -> anyhow::Result<Command> {
+        self.set_defaults(command, &mut BTreeSet::new())
+    }
+
+    fn set_defaults<'a>(
+        &'a self,
+        command: Command,
+        unknown: &mut BTreeSet<&'a String>,
+    ) -> anyhow::Result<Command> {
+        let mut error = None;
+        let mut apply_args = |command: Command| {
+            command.mut_args(|arg| {
+                if arg.is_positional() {
+                    return arg;
+                }
+                let id = arg.get_id().as_str().to_owned();
+                let arg = arg.env(format!("{}{}", ENV_PREFIX, id.to_uppercase()));
+                let Some(setting) = self.settings.get(&id) else {
+                    return arg;
+                };
+                unknown.remove(&id);
+                match arg_values(&id, &setting.value) {
+                    Ok(values) => arg.default_values(values),
+                    Err(e) => {
+                        error = Some(e);
+                        arg
+                    }
+                }
+            })
+        };
+
+        let mut command = apply_args(command);
+        for subcommand in command.get_subcommands_mut() {
+            *subcommand = apply_args(std::mem::take(subcommand));
+        }
+        if let Some(e) = error {
+            return Err(e);
+        }
+        Ok(command)
+    }
+
+    pub(crate) fn show(&self, command: &Command) {
+        for file in &self.files {
+            println!("# config: {}", file.display());
+        }
+        if let Some(profile) = &self.profile {
+            println!("# profile: {}", profile);
+        }
+
+        let mut shown = BTreeSet::new();
+        let args = command
+            .get_arguments()
+            .chain(command.get_subcommands().flat_map(Command::get_arguments));
+        for arg in args {
+            let id = arg.get_id().as_str();
+            if arg.is_positional() || ["config", "profile"].contains(&id) || !shown.insert(id) {
+                continue;
+            }
+            let env_value = arg
+                .get_env()
+                .and_then(|name| env::var_os(name).map(|value| (name, value)));
+            let (value, source) = if let Some((name, value)) = env_value {
+                (
+                    display_value(&value.to_string_lossy()),
+                    name.to_string_lossy().into_owned(),
+                )
+            } else if let Some(setting) = self.settings.get(id) {
+                (setting.value.to_string(), setting.source.clone())
+            } else if let [value] = arg.get_default_values() {
+                (
+                    display_value(&value.to_string_lossy()),
+                    "default".to_owned(),
+                )
+            } else {
+                continue;
+            };
+            println!("{} = {}  # {}", id, value, source);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{CommandFactory, FromArgMatches, Parser};
+
+    #[derive(Parser)]
+    struct TestCli {
+        #[arg(long)]
+        config: Option<PathBuf>,
+        #[arg(long)]
+        profile: Option<String>,
+        #[arg(long, default_value = "default")]
+        from_default: String,
+        #[arg(long, default_value = "default")]
+        from_file: String,
+        #[arg(long, default_value = "default")]
+        from_profile: String,
+        #[arg(long, default_value = "default")]
+        from_env: String,
+        #[arg(long, default_value = "default")]
+        from_cli: String,
+    }
+
+    fn write_config(name: &str, content: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("annotai-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn os_args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn later_sources_take_precedence() {
+        let path = write_config(
+            "config-precedence",
+            r#"
+            from_file = "file"
+            from_profile = "file"
+            from_env = "file"
+            from-cli = "file"
+
+            [profiles.test]
+            from-profile = "profile"
+            from_env = "profile"
+            from_cli = "profile"
+            "#,
+        );
+        env::set_var("ANNOTAI_FROM_ENV", "env");
+        let path = path.to_str().unwrap();
+        let args = os_args(&[
+            "annotai",
+            "--config",
+            path,
+            "--profile",
+            "test",
+            "--from-cli",
+            "cli",
+        ]);
+        let config = Config::load(&args).unwrap();
+        let matches = config
+            .apply(TestCli::command())
+            .unwrap()
+            .get_matches_from(&args);
+        let cli = TestCli::from_arg_matches(&matches).unwrap();
+        assert_eq!(cli.from_default, "default");
+        assert_eq!(cli.from_file, "file");
+        assert_eq!(cli.from_profile, "profile");
+        assert_eq!(cli.from_env, "env");
+        assert_eq!(cli.from_cli, "cli");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn profiles_apply_only_when_selected() {
+        let path = write_config(
+            "config-profile",
+            r#"
+            from_profile = "file"
+
+            [profiles.test]
+            from_profile = "profile"
+            "#,
+        );
+        let path = path.to_str().unwrap();
+        let args = os_args(&["annotai", "--config", path]);
+        let matches = Config::load(&args)
+            .unwrap()
+            .apply(TestCli::command())
+            .unwrap()
+            .get_matches_from(&args);
+        let cli = TestCli::from_arg_matches(&matches).unwrap();
+        assert_eq!(cli.from_profile, "file");
+
+        let args = os_args(&["annotai", "--config", path, "--profile=other"]);
+        let error = Config::load(&args).err().unwrap().to_string();
+        assert_eq!(error, "Unknown config profile: other");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn unknown_settings_are_errors() {
+        let path = write_config("config-unknown", "from_file = \"file\"\nno_such_flag = 1\n");
+        let path = path.to_str().unwrap();
+        let args = os_args(&["annotai", "--config", path]);
+        let config = Config::load(&args).unwrap();
+        let error = config.apply(TestCli::command()).err().unwrap().to_string();
+        assert_eq!(error, format!("Unknown setting no_such_flag in {}", path));
+        // Commands that take only some of the settings ignore the others.
+        assert!(config.apply_known(TestCli::command()).is_ok());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn config_values_become_arguments() {
+        assert_eq!(
+            arg_values("languages", &toml::Value::try_from(["en", "ja"]).unwrap()).unwrap(),
+            ["en", "ja"]
+        );
+        assert_eq!(
+            arg_values("volume", &toml::Value::Float(0.8)).unwrap(),
+            ["0.8"]
+        );
+        assert_eq!(
+            arg_values("speak", &toml::Value::Boolean(true)).unwrap(),
+            ["true"]
+        );
+        assert!(arg_values("table", &toml::Value::Table(toml::Table::new())).is_err());
+    }
+}
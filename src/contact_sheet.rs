@@ -0,0 +1,220 @@
+use image::imageops::{self, FilterType};
+use image::{Rgb, RgbImage};
+use std::path::{Path, PathBuf};
+
+use crate::video;
+
+const TILE_WIDTH: u32 = 320;
+const GAP: u32 = 4;
+const BACKGROUND: Rgb<u8> = Rgb([32, 32, 32]);
+
+pub(crate) struct ContactSheet {
+    pub(crate) path: PathBuf,
+    pub(crate) first_msec: i64,
+    pub(crate) last_msec: i64,
+    pub(crate) frame_count: usize,
+}
+
+/// 3x5 glyphs for the timestamp labels, one row of three bits per byte.
+fn glyph(c: char) -> Option<[u8; 5]> {
+    Some(match c {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b001, 0b001, 0b001],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        's' => [0b000, 0b011, 0b100, 0b001, 0b110],
+        _ => return None,
+    })
+}
+
+/// Draws `text` in white on a black box in the bottom left corner of the tile.
+fn draw_label(tile: &mut RgbImage, text: &str) {
+    let scale = (tile.width() / 160).max(2);
+    let (glyph_width, glyph_height) = (4 * scale, 5 * scale);
+    let box_width = (text.chars().count() as u32 * glyph_width + scale * 2).min(tile.width());
+    let box_height = (glyph_height + scale * 2).min(tile.height());
+    let box_top = tile.height() - box_height;
+    for y in box_top..tile.height() {
+        for x in 0..box_width {
+            tile.put_pixel(x, y, Rgb([0, 0, 0]));
+        }
+    }
+    for (index, rows) in text.chars().filter_map(glyph).enumerate() {
+        let left = scale + index as u32 * glyph_width;
+        for (row, bits) in rows.iter().enumerate() {
+            for column in 0..3 {
+                if bits & (0b100 >> column) == 0 {
+                    continue;
+                }
+                for dy in 0..scale {
+                    for dx in 0..scale {
+                        let (x, y) = (
+                            left + column * scale + dx,
+                            box_top + scale + row as u32 * scale + dy,
+                        );
+                        if x < tile.width() && y < tile.height() {
+                            tile.put_pixel(x, y, Rgb([255, 255, 255]));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Tiles the frames into grids of `columns` by `rows`, left to right and top to bottom, each
+/// frame labelled with its time, and writes them next to the frames.
+pub(crate) fn write_contact_sheets(
+    frames: &[video::CapturedFrame],
+    columns: u32,
+    rows: u32,
+    output_dir: &Path,
+) -> anyhow::Result<Vec<ContactSheet>> {
+    if columns == 0 || rows == 0 {
+        anyhow::bail!("Invalid contact sheet layout: {}x{}", columns, rows);
+    }
+    let Some(first_frame) = frames.first() else {
+        return Ok(Vec::new());
+    };
+    let first_image = image::open(&first_frame.path)?;
+    let tile_height = (TILE_WIDTH as u64 * first_image.height() as u64
+        / first_image.width().max(1) as u64)
+        .max(1) as u32;
+
+    let mut sheets = Vec::new();
+    for (index, chunk) in frames.chunks((columns * rows) as usize).enumerate() {
+        let sheet_columns = columns.min(chunk.len() as u32);
+        let sheet_rows = (chunk.len() as u32).div_ceil(columns);
+        let mut sheet = RgbImage::from_pixel(
+            GAP + sheet_columns * (TILE_WIDTH + GAP),
+            GAP + sheet_rows * (tile_height + GAP),
+            BACKGROUND,
+        );
+        for (position, frame) in chunk.iter().enumerate() {
+            let mut tile = imageops::resize(
+                &image::open(&frame.path)?.to_rgb8(),
+                TILE_WIDTH,
+                tile_height,
+                FilterType::Triangle,
+            );
+            draw_label(
+                &mut tile,
+                &format!("{:.1}s", frame.timestamp_msec as f64 / 1000.0),
+            );
+            let (column, row) = (position as u32 % columns, position as u32 / columns);
+            imageops::overlay(
+                &mut sheet,
+                &tile,
+                (GAP + column * (TILE_WIDTH + GAP)) as i64,
+                (GAP + row * (tile_height + GAP)) as i64,
+            );
+        }
+        let path = output_dir.join(format!("contact_sheet_{:02}.jpg", index));
+        sheet.save(&path)?;
+        sheets.push(ContactSheet {
+            path,
+            first_msec: chunk[0].timestamp_msec,
+            last_msec: chunk[chunk.len() - 1].timestamp_msec,
+            frame_count: chunk.len(),
+        });
+    }
+    Ok(sheets)
+}
+
+pub(crate) fn prompt_section(sheet_count: usize, frame_count: usize) -> String {
+    format!(
+        "\n\nThe {} frames are tiled into {} contact sheets. \
+         Read each sheet left to right and top to bottom; \
+         the label in the corner of every frame is its time in seconds.",
+        frame_count, sheet_count
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn frames_are_tiled_in_reading_order() {
+        let dir = std::env::temp_dir().join(format!("annotai-sheets-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        // Seven 16:9 frames, each a little redder than the last.
+        let frames: Vec<_> = (0..7)
+            .map(|index| {
+                let path = dir.join(format!("frame_{:04}.png", index));
+                RgbImage::from_pixel(64, 36, Rgb([index as u8 * 40, 0, 0]))
+                    .save(&path)
+                    .unwrap();
+                video::CapturedFrame {
+                    path,
+                    timestamp_msec: index * 500,
+                    base64: String::new(),
+                }
+            })
+            .collect();
+
+        let sheets = write_contact_sheets(&frames, 3, 2, &dir).unwrap();
+        assert_eq!(sheets.len(), 2);
+        assert_eq!(
+            (
+                sheets[0].first_msec,
+                sheets[0].last_msec,
+                sheets[0].frame_count
+            ),
+            (0, 2500, 6)
+        );
+        assert_eq!(
+            (
+                sheets[1].first_msec,
+                sheets[1].last_msec,
+                sheets[1].frame_count
+            ),
+            (3000, 3000, 1)
+        );
+        assert_eq!(sheets[1].path, dir.join("contact_sheet_01.jpg"));
+
+        let tile_height = 180;
+        let sheet = image::open(&sheets[0].path).unwrap().to_rgb8();
+        assert_eq!(
+            sheet.dimensions(),
+            (GAP + 3 * (TILE_WIDTH + GAP), GAP + 2 * (tile_height + GAP))
+        );
+        for position in 0..6 {
+            let (column, row) = (position % 3, position / 3);
+            let Rgb([red, _, _]) = *sheet.get_pixel(
+                GAP + column * (TILE_WIDTH + GAP) + TILE_WIDTH / 2,
+                GAP + row * (tile_height + GAP) + tile_height / 4,
+            );
+            assert!(
+                (red as i32 - position as i32 * 40).abs() < 8,
+                "tile {} has red {}",
+                position,
+                red
+            );
+        }
+        // The last sheet is only as large as the frames on it.
+        let sheet = image::open(&sheets[1].path).unwrap();
+        assert_eq!(
+            (sheet.width(), sheet.height()),
+            (GAP + TILE_WIDTH + GAP, GAP + tile_height + GAP)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn empty_layouts_are_errors() {
+        assert!(write_contact_sheets(&[], 0, 2, Path::new(".")).is_err());
+        assert!(write_contact_sheets(&[], 3, 2, Path::new("."))
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,85 @@
+const LANGUAGES: &[(&str, &str, &str)] = &[
+    ("ar", "ara", "Arabic"),
+    ("de", "deu", "German"),
+    ("en", "eng", "English"),
+    ("es", "spa", "Spanish"),
+    ("fr", "fra", "French"),
+    ("hi", "hin", "Hindi"),
+    ("id", "ind", "Indonesian"),
+    ("it", "ita", "Italian"),
+    ("ja", "jpn", "Japanese"),
+    ("ko", "kor", "Korean"),
+    ("nl", "nld", "Dutch"),
+    ("pl", "pol", "Polish"),
+    ("pt", "por", "Portuguese"),
+    ("ru", "rus", "Russian"),
+    ("sv", "swe", "Swedish"),
+    ("th", "tha", "Thai"),
+    ("tr", "tur", "Turkish"),
+    ("uk", "ukr", "Ukrainian"),
+    ("vi", "vie", "Vietnamese"),
+    ("zh", "zho", "Chinese"),
+];
+
+#[derive(Clone, Debug)]
+pub(crate) struct Language {
+    pub(crate) tag: String,
+    pub(crate) name: String,
+    pub(crate) iso639_2: Option<&'static str>,
+}
+
+/// Checks a language tag given by the user, which ends up in file names and filter graphs.
+pub(crate) fn parse_tag(tag: &str) -> anyhow::Result<String> {
+    let tag = tag.trim();
+    if tag.is_empty() || !tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        anyhow::bail!("Invalid language tag: {:?}", tag);
+    }
+    Ok(tag.to_owned())
+}
+
+impl Language {
+    pub(crate) fn new(tag: &str) -> anyhow::Result<Self> {
+        let tag = parse_tag(tag)?;
+        let known = LANGUAGES.iter().find(|(iso639_1, iso639_2, name)| {
+            tag.eq_ignore_ascii_case(iso639_1)
+                || tag.eq_ignore_ascii_case(iso639_2)
+                || tag.eq_ignore_ascii_case(name)
+        });
+        Ok(match known {
+            Some(&(iso639_1, iso639_2, name)) => Self {
+                tag: iso639_1.to_owned(),
+                name: name.to_owned(),
+                iso639_2: Some(iso639_2),
+            },
+            None => Self {
+                name: tag.clone(),
+                tag,
+                iso639_2: None,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_languages_are_found_by_any_name() {
+        for tag in ["ja", "JPN", "Japanese", " ja "] {
+            let language = Language::new(tag).unwrap();
+            assert_eq!(language.tag, "ja");
+            assert_eq!(language.iso639_2, Some("jpn"));
+        }
+    }
+
+    #[test]
+    fn other_tags_are_kept_when_safe() {
+        let language = Language::new("pt-BR").unwrap();
+        assert_eq!(language.tag, "pt-BR");
+        assert_eq!(language.iso639_2, None);
+        for tag in ["", "../en", "en,amovie=x", "en;", "e n"] {
+            assert!(Language::new(tag).is_err(), "{:?}", tag);
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+use crate::cache::Cache;
+use crate::language::Language;
+use crate::{ai, pipeline, prompt, video};
+
+#[derive(clap::Args)]
+pub(crate) struct LiveArgs {
+    /// Stream to annotate, e.g. rtmp://, rtsp://, srt:// or udp:// URL
+    url: String,
+    /// How much of the latest stream is shown to the model
+    #[arg(long, default_value_t = 10)]
+    window_sec: u64,
+    /// How often to annotate the latest window
+    #[arg(long, default_value_t = 10)]
+    every_sec: u64,
+    /// Also synthesize the commentary as speech
+    #[arg(long)]
+    speak: bool,
+    /// Pass the stream on to this rtmp://, srt:// or udp:// URL with the spoken commentary mixed in
+    #[arg(long)]
+    restream: Option<String>,
+    /// How long the restreamed output lags behind the source, to give the model time to respond
+    #[arg(long, default_value_t = 20)]
+    delay_sec: u64,
+    /// Where to write events.jsonl and the speech
+    #[arg(long, default_value = "output/live")]
+    output_dir: PathBuf,
+    #[arg(long, default_value_t = 500)]
+    capture_interval_msec: i64,
+    /// Volume of the source audio under the commentary when restreaming
+    #[arg(long, default_value_t = 0.8)]
+    source_volume: f64,
+    #[arg(long, default_value_t = 1.2)]
+    commentary_volume: f64,
+    #[command(flatten)]
+    commentary: pipeline::CommentaryOptions,
+}
+
+/// One commentary, written as a line of events.jsonl and to stdout.
+#[derive(Serialize)]
+struct CommentaryEvent {
+    /// Wall-clock times in milliseconds since the Unix epoch
+    window_start_msec: u128,
+    window_end_msec: u128,
+    emitted_msec: u128,
+    language: String,
+    text: String,
+    audio_path: Option<PathBuf>,
+}
+
+fn unix_msec(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+async fn annotate_window(
+    args: &LiveArgs,
+    languages: &[Language],
+    frames: &VecDeque<video::LiveFrame>,
+    event_count: usize,
+    events: &mut fs::File,
+    commentary_sender: Option<&std::sync::mpsc::Sender<video::CommentaryAudio>>,
+    cache: &Cache,
+) -> anyhow::Result<()> {
+    let options = &args.commentary;
+    let (Some(first), Some(last), Some(primary_language)) =
+        (frames.front(), frames.back(), languages.first())
+    else {
+        return Ok(());
+    };
+    let prompt = options.prompt_source(None).render(&prompt::PromptVars {
+        duration_sec: args.window_sec as i64,
+        start_sec: 0,
+        frame_count: frames.len(),
+        filename: &args.url,
+        language: &primary_language.name,
+    })?;
+    let base64_frames = frames.iter().map(|frame| frame.base64.clone()).collect();
+    let comment = ai::annotation_frames(&prompt, base64_frames, &[], &options.ai, cache).await?;
+
+    for language in languages {
+        let text = if language.tag == primary_language.tag {
+            comment.clone()
+        } else {
+            ai::translate_comment(&comment, &language.name, &options.ai, cache).await?
+        };
+        // The restreamed output carries the commentary in the first language only.
+        let restreamed = commentary_sender.filter(|_| language.tag == primary_language.tag);
+        let audio_path = if args.speak || restreamed.is_some() {
+            let path = args
+                .output_dir
+                .join(format!("comment.{}.{:05}.mp3", language.tag, event_count));
+            ai::audio_speech(&text, &path, 1.0, &options.ai, cache).await?;
+            if let Some(sender) = restreamed {
+                // Decoded here so that the thread reading the source never waits on it.
+                let samples = {
+                    let path = path.clone();
+                    tokio::task::spawn_blocking(move || video::commentary_samples(&path)).await??
+                };
+                sender.send(video::CommentaryAudio {
+                    start_at: last.captured_at,
+                    samples,
+                })?;
+            }
+            Some(path)
+        } else {
+            None
+        };
+        let event = CommentaryEvent {
+            window_start_msec: unix_msec(first.captured_at),
+            window_end_msec: unix_msec(last.captured_at),
+            emitted_msec: unix_msec(SystemTime::now()),
+            language: language.tag.clone(),
+            text,
+            audio_path,
+        };
+        let line = serde_json::to_string(&event)?;
+        println!("{}", line);
+        writeln!(events, "{}", line)?;
+    }
+    Ok(())
+}
+
+pub(crate) async fn live(args: LiveArgs) -> anyhow::Result<()> {
+    if args.capture_interval_msec <= 0 {
+        anyhow::bail!(
+            "Invalid capture interval: {} ms",
+            args.capture_interval_msec
+        );
+    }
+    let languages = args
+        .commentary
+        .languages
+        .iter()
+        .map(|tag| Language::new(tag))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if languages.is_empty() {
+        anyhow::bail!("At least one language is required");
+    }
+    // Every window is new, so there is nothing worth caching.
+    let cache = Cache::disabled();
+    fs::create_dir_all(&args.output_dir)?;
+    let mut events = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(args.output_dir.join("events.jsonl"))?;
+
+    video::init();
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let (commentary_sender, restream) = match &args.restream {
+        Some(url) => {
+            let (commentary_sender, commentary_receiver) = std::sync::mpsc::channel();
+            let restream = video::Restream {
+                url: url.clone(),
+                delay_msec: args.delay_sec * 1000,
+                source_volume: args.source_volume,
+                commentary_volume: args.commentary_volume,
+                commentary: commentary_receiver,
+            };
+            (Some(commentary_sender), Some(restream))
+        }
+        None => (None, None),
+    };
+    let capture = {
+        let url = args.url.clone();
+        let interval_msec = args.capture_interval_msec;
+        tokio::task::spawn_blocking(move || {
+            video::capture_live(&url, interval_msec, restream, |frame| {
+                sender.send(frame).is_ok()
+            })
+        })
+    };
+    println!("Annotating {}", args.url);
+
+    let window = Duration::from_secs(args.window_sec);
+    let every = Duration::from_secs(args.every_sec);
+    let mut next_annotation = Instant::now() + window;
+    let mut frames: VecDeque<video::LiveFrame> = VecDeque::new();
+    let mut event_count = 0;
+    while let Some(frame) = receiver.recv().await {
+        frames.push_back(frame);
+        // Catch up on what arrived while the model was busy before looking at the window.
+        while let Ok(frame) = receiver.try_recv() {
+            frames.push_back(frame);
+        }
+        let Some(window_end) = frames.back().map(|frame| frame.captured_at) else {
+            continue;
+        };
+        while frames.front().is_some_and(|frame| {
+            window_end
+                .duration_since(frame.captured_at)
+                .unwrap_or_default()
+                > window
+        }) {
+            frames.pop_front();
+        }
+        if Instant::now() < next_annotation {
+            continue;
+        }
+        next_annotation = Instant::now() + every;
+        if let Err(e) = annotate_window(
+            &args,
+            &languages,
+            &frames,
+            event_count,
+            &mut events,
+            commentary_sender.as_ref(),
+            &cache,
+        )
+        .await
+        {
+            // A slow or failed request should not end the broadcast.
+            eprintln!("Failed to annotate the window: {:#}", e);
+        }
+        event_count += 1;
+    }
+
+    capture.await??;
+    println!("Stream ended: {}", args.url);
+    Ok(())
+}
@@ -1,54 +1,114 @@
 mod ai;
+mod cache;
+mod chapter;
+mod config;
+mod contact_sheet;
+mod language;
+mod live;
+mod manifest;
+mod pipeline;
+mod prompt;
+mod server;
+mod speech;
+mod subtitle;
+mod tonemap;
+mod transcript;
 mod video;
+mod watch;
 
-use clap::Parser;
-use std::fs;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use std::env;
+use std::ffi::OsString;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 #[derive(Parser)]
 #[command(name = "annotai")]
 #[command(about = "Annotate videos using OpenAI's GPT-4o", long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Cli {
-    input_file: PathBuf,
-    #[arg(short, long)]
-    prompt: String,
-    #[arg(short, long, default_value_t = 0)]
-    start_sec: i64,
-    #[arg(short, long, default_value_t = 30)]
-    duration_sec: i64,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Videos to annotate, each optionally cut as PATH@START+DURATION (seconds); several are joined into one
+    #[arg(required = true)]
+    input_files: Vec<video::Clip>,
+    /// Read settings from this file instead of annotai.toml in the user config dir and the current dir
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Apply the settings of this profile from the config file
+    #[arg(long, global = true)]
+    profile: Option<String>,
+    #[command(flatten)]
+    options: pipeline::Options,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Serve annotation jobs over HTTP
+    Serve(server::ServeArgs),
+    /// Annotate videos as they appear in a directory
+    Watch(Box<watch::WatchArgs>),
+    /// Annotate a live stream as it plays
+    Live(Box<live::LiveArgs>),
+    /// Inspect the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print the effective configuration and where each value comes from
+    Show,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
-    fs::exists(&cli.input_file)?;
-    fs::create_dir_all("output")?;
+    let args: Vec<OsString> = env::args_os().collect();
+    let config = config::Config::load(&args)?;
+    let command = config.apply(Cli::command())?;
+    let cli = Cli::from_arg_matches(&command.clone().get_matches_from(&args))
+        .unwrap_or_else(|e| e.exit());
+    match cli.command {
+        Some(Command::Serve(args)) => return server::serve(args, &config).await,
+        Some(Command::Watch(args)) => return watch::watch(*args).await,
+        Some(Command::Live(args)) => return live::live(*args).await,
+        Some(Command::Config(ConfigCommand::Show)) => {
+            config.show(&command);
+            return Ok(());
+        }
+        None => {}
+    }
+    if cli.input_files.is_empty() {
+        anyhow::bail!("An input file is required");
+    }
 
-    let capture_interval_msec = 500;
-    video::init();
-    let frames = video::capture_base64(
-        cli.input_file.as_path(),
-        cli.start_sec,
-        cli.duration_sec,
-        capture_interval_msec,
+    let cache = if cli.options.no_cache {
+        cache::Cache::disabled()
+    } else {
+        cache::Cache::new("output/cache")
+    };
+    let pruned = cache.prune(
+        Duration::from_secs(cli.options.cache_max_age_days * 24 * 60 * 60),
+        cli.options.cache_max_size_mb * 1024 * 1024,
     )?;
+    if pruned > 0 {
+        println!("Pruned cache entries: {}", pruned);
+    }
 
-    println!("Captured frames: {}", frames.len());
-
-    let comment = ai::annotation_frames(&cli.prompt, frames).await?;
-
-    println!("AI Comment: {}", comment);
-
-    let comment_audio_path = Path::new("output/comment.mp3");
-    ai::audio_speech(&comment, comment_audio_path).await?;
-
-    let transcoded_path = Path::new("output/transcoded.mp4");
-    video::transcode(
-        cli.input_file.as_path(),
-        comment_audio_path,
-        transcoded_path,
-        cli.start_sec,
-        cli.duration_sec * 2,
-    )?;
+    match cli.input_files.as_slice() {
+        [clip] if clip.range.is_none() => {
+            pipeline::run(
+                &clip.path,
+                &cli.options,
+                Path::new("output"),
+                &cache,
+                &|_| {},
+            )
+            .await?;
+        }
+        clips => {
+            pipeline::run_clips(clips, &cli.options, Path::new("output"), &cache, &|_| {}).await?;
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,300 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::transcript::{Transcriber, TranscriptSegment};
+use crate::video;
+
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum, Serialize, Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Stage {
+    Capture,
+    Annotate,
+    Tts,
+    Mix,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    pub(crate) input_file: PathBuf,
+    pub(crate) start_sec: i64,
+    pub(crate) duration_sec: i64,
+    /// The clips joined into the input file, when there are several
+    #[serde(default)]
+    pub(crate) clips: Vec<JoinedClip>,
+    /// Whether the clips are still images, each narrated on its own
+    #[serde(default)]
+    pub(crate) stills: bool,
+    pub(crate) capture: Option<CaptureStage>,
+    pub(crate) transcript: Option<TranscriptStage>,
+    pub(crate) annotate: Option<AnnotateStage>,
+    pub(crate) tts: Option<TtsStage>,
+    pub(crate) mix: Option<MixStage>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct JoinedClip {
+    pub(crate) path: PathBuf,
+    pub(crate) start_sec: i64,
+    pub(crate) offset_msec: i64,
+    pub(crate) duration_msec: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct CaptureStage {
+    pub(crate) interval_msec: i64,
+    pub(crate) frames: Vec<CaptureFrame>,
+    #[serde(default)]
+    pub(crate) contact_sheets: Vec<CaptureSheet>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct CaptureFrame {
+    pub(crate) path: PathBuf,
+    pub(crate) timestamp_msec: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct CaptureSheet {
+    pub(crate) path: PathBuf,
+    pub(crate) first_msec: i64,
+    pub(crate) last_msec: i64,
+    pub(crate) frame_count: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TranscriptStage {
+    #[serde(default)]
+    pub(crate) transcriber: Option<Transcriber>,
+    pub(crate) audio_path: PathBuf,
+    pub(crate) segments: Vec<TranscriptSegment>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct AnnotateStage {
+    pub(crate) system_prompt: Option<String>,
+    pub(crate) prompt: Option<String>,
+    pub(crate) comments: Vec<LanguageComment>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct LanguageComment {
+    pub(crate) language: String,
+    pub(crate) segments: Vec<CommentSegment>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct CommentSegment {
+    pub(crate) start_msec: i64,
+    pub(crate) window_msec: i64,
+    pub(crate) comment_path: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct TtsStage {
+    pub(crate) tracks: Vec<SpeechTrack>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SpeechTrack {
+    pub(crate) language: String,
+    pub(crate) clips: Vec<SpeechClip>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct SpeechClip {
+    pub(crate) start_msec: i64,
+    pub(crate) window_msec: i64,
+    pub(crate) comment_path: Option<PathBuf>,
+    pub(crate) spoken_comment: Option<String>,
+    pub(crate) comment_audio_path: PathBuf,
+    #[serde(default)]
+    pub(crate) speed_fitted: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct MixStage {
+    pub(crate) comment_audio_paths: Vec<PathBuf>,
+    pub(crate) subtitle_paths: Vec<PathBuf>,
+    #[serde(default)]
+    pub(crate) chapters_path: Option<PathBuf>,
+    pub(crate) output_path: PathBuf,
+}
+
+impl Manifest {
+    pub(crate) fn new(input_file: &Path, start_sec: i64, duration_sec: i64) -> Self {
+        Self {
+            input_file: input_file.to_owned(),
+            start_sec,
+            duration_sec,
+            clips: Vec::new(),
+            stills: false,
+            capture: None,
+            transcript: None,
+            annotate: None,
+            tts: None,
+            mix: None,
+        }
+    }
+
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Self> {
+        let manifest = serde_json::from_slice(&fs::read(path).map_err(|e| {
+            anyhow::anyhow!("Failed to read run manifest {}: {}", path.display(), e)
+        })?)?;
+        Ok(manifest)
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub(crate) fn check_input(
+        &self,
+        input_file: &Path,
+        start_sec: i64,
+        duration_sec: i64,
+    ) -> anyhow::Result<()> {
+        if self.input_file != input_file
+            || self.start_sec != start_sec
+            || self.duration_sec != duration_sec
+        {
+            anyhow::bail!(
+                "Run manifest was recorded for {} ({}s + {}s); rerun from the capture stage",
+                self.input_file.display(),
+                self.start_sec,
+                self.duration_sec
+            );
+        }
+        Ok(())
+    }
+
+    pub(crate) fn captured_frames(&self) -> anyhow::Result<Vec<video::CapturedFrame>> {
+        let capture = self
+            .capture
+            .as_ref()
+            .ok_or(anyhow::anyhow!("No capture stage in run manifest"))?;
+        capture
+            .frames
+            .iter()
+            .map(|frame| {
+                Ok(video::CapturedFrame {
+                    path: frame.path.clone(),
+                    timestamp_msec: frame.timestamp_msec,
+                    base64: video::jpeg_base64(&fs::read(&frame.path)?),
+                })
+            })
+            .collect()
+    }
+
+    /// The contact sheets as frames at the time of their first tile.
+    pub(crate) fn contact_sheets(&self) -> anyhow::Result<Vec<video::CapturedFrame>> {
+        let capture = self
+            .capture
+            .as_ref()
+            .ok_or(anyhow::anyhow!("No capture stage in run manifest"))?;
+        if capture.contact_sheets.is_empty() {
+            anyhow::bail!("No contact sheets in run manifest; rerun from the capture stage");
+        }
+        capture
+            .contact_sheets
+            .iter()
+            .map(|sheet| {
+                Ok(video::CapturedFrame {
+                    path: sheet.path.clone(),
+                    timestamp_msec: sheet.first_msec,
+                    base64: video::jpeg_base64(&fs::read(&sheet.path)?),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("annotai-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn manifests_are_loaded_as_saved() {
+        let dir = test_dir("manifest");
+        let path = dir.join("manifest.json");
+        let mut manifest = Manifest::new(Path::new("input.mp4"), 30, 60);
+        manifest.capture = Some(CaptureStage {
+            interval_msec: 500,
+            frames: vec![CaptureFrame {
+                path: dir.join("frame_0000.jpg"),
+                timestamp_msec: 0,
+            }],
+            contact_sheets: Vec::new(),
+        });
+        manifest.save(&path).unwrap();
+
+        let loaded = Manifest::load(&path).unwrap();
+        assert_eq!(loaded.input_file, Path::new("input.mp4"));
+        assert_eq!((loaded.start_sec, loaded.duration_sec), (30, 60));
+        let capture = loaded.capture.unwrap();
+        assert_eq!(capture.interval_msec, 500);
+        assert_eq!(capture.frames.len(), 1);
+        assert!(loaded.annotate.is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn manifests_without_newer_fields_are_loaded() {
+        let dir = test_dir("manifest-old");
+        let path = dir.join("manifest.json");
+        fs::write(
+            &path,
+            r#"{"input_file": "input.mp4", "start_sec": 0, "duration_sec": 10,
+                "capture": null, "transcript": null, "annotate": null, "tts": null, "mix": null}"#,
+        )
+        .unwrap();
+        let manifest = Manifest::load(&path).unwrap();
+        assert!(manifest.clips.is_empty());
+        assert!(!manifest.stills);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_or_damaged_manifests_are_errors() {
+        let dir = test_dir("manifest-bad");
+        let path = dir.join("manifest.json");
+        let error = Manifest::load(&path).err().unwrap().to_string();
+        assert!(
+            error.starts_with("Failed to read run manifest"),
+            "{}",
+            error
+        );
+        fs::write(&path, "{").unwrap();
+        assert!(Manifest::load(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn manifests_are_checked_against_the_input() {
+        let manifest = Manifest::new(Path::new("input.mp4"), 30, 60);
+        assert!(manifest.check_input(Path::new("input.mp4"), 30, 60).is_ok());
+        assert!(manifest
+            .check_input(Path::new("other.mp4"), 30, 60)
+            .is_err());
+        assert!(manifest.check_input(Path::new("input.mp4"), 0, 60).is_err());
+        let error = manifest
+            .check_input(Path::new("input.mp4"), 30, 90)
+            .err()
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            error,
+            "Run manifest was recorded for input.mp4 (30s + 60s); rerun from the capture stage"
+        );
+    }
+}
@@ -0,0 +1,965 @@
+use clap::Parser;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cache::Cache;
+use crate::language::{self, Language};
+use crate::manifest::{
+    AnnotateStage, CaptureFrame, CaptureSheet, CaptureStage, CommentSegment, JoinedClip,
+    LanguageComment, Manifest, MixStage, SpeechClip, SpeechTrack, Stage, TranscriptStage, TtsStage,
+};
+use crate::{ai, chapter, contact_sheet, prompt, speech, subtitle, transcript, video};
+
+/// How the commentary is written and spoken, shared with `annotai live`.
+#[derive(Clone, clap::Args)]
+pub(crate) struct CommentaryOptions {
+    #[arg(short, long, conflicts_with = "prompt_file")]
+    pub(crate) prompt: Option<String>,
+    /// Read the prompt template from a file
+    #[arg(long)]
+    pub(crate) prompt_file: Option<PathBuf>,
+    #[arg(long, conflicts_with = "system_prompt_file")]
+    pub(crate) system_prompt: Option<String>,
+    /// Read the system prompt template from a file
+    #[arg(long)]
+    pub(crate) system_prompt_file: Option<PathBuf>,
+    #[arg(long, value_enum)]
+    pub(crate) preset: Option<prompt::Preset>,
+    /// Commentary languages; the first one is generated and the others translated from it
+    #[arg(long, value_delimiter = ',', default_value = "en", value_parser = language::parse_tag)]
+    pub(crate) languages: Vec<String>,
+    #[command(flatten)]
+    pub(crate) ai: ai::Settings,
+}
+
+impl CommentaryOptions {
+    pub(crate) fn prompt_source(
+        &self,
+        default_preset: Option<prompt::Preset>,
+    ) -> prompt::PromptSource<'_> {
+        prompt::PromptSource {
+            preset: self.preset.or(default_preset),
+            prompt: self.prompt.as_deref(),
+            prompt_file: self.prompt_file.as_deref(),
+            system_prompt: self.system_prompt.as_deref(),
+            system_prompt_file: self.system_prompt_file.as_deref(),
+        }
+    }
+}
+
+#[derive(Clone, Parser)]
+pub(crate) struct Options {
+    #[command(flatten)]
+    pub(crate) commentary: CommentaryOptions,
+    /// Generate the commentary of every language from the frames instead of translating it
+    #[arg(long)]
+    pub(crate) annotate_each_language: bool,
+    /// Review the commentary and ask the model for changes before it is spoken
+    #[arg(long, conflicts_with_all = ["comment_text", "annotate_each_language"])]
+    pub(crate) interactive: bool,
+    /// Narrate only in quiet gaps of the original audio
+    #[arg(long, conflicts_with = "comment_text")]
+    pub(crate) audio_description: bool,
+    /// Loudness below which the original audio counts as quiet
+    #[arg(long, default_value_t = -35.0, allow_hyphen_values = true)]
+    pub(crate) silence_db: f64,
+    #[arg(long, default_value_t = 1500)]
+    pub(crate) min_gap_msec: i64,
+    /// Transcribe the original audio and give the transcript to the model
+    #[arg(long, value_enum)]
+    pub(crate) transcribe: Option<transcript::Transcriber>,
+    /// whisper.cpp command used by the local transcriber
+    #[arg(long, default_value = "whisper-cli")]
+    pub(crate) whisper_command: String,
+    /// whisper.cpp model used by the local transcriber
+    #[arg(long)]
+    pub(crate) whisper_model: Option<PathBuf>,
+    #[arg(short, long, default_value_t = 0)]
+    pub(crate) start_sec: i64,
+    #[arg(short, long, default_value_t = 30)]
+    pub(crate) duration_sec: i64,
+    #[arg(short, long, value_enum, default_value_t = video::LengthPolicy::Continue)]
+    pub(crate) length_policy: video::LengthPolicy,
+    #[arg(long, value_enum, default_value_t = speech::SpeechFit::Tempo)]
+    pub(crate) speech_fit: speech::SpeechFit,
+    #[arg(long, default_value_t = 1.0)]
+    pub(crate) min_tempo: f64,
+    #[arg(long, default_value_t = 1.25)]
+    pub(crate) max_tempo: f64,
+    #[arg(long, default_value_t = 2)]
+    pub(crate) shorten_attempts: u32,
+    #[arg(long)]
+    pub(crate) no_cache: bool,
+    #[arg(long, default_value_t = 30)]
+    pub(crate) cache_max_age_days: u64,
+    #[arg(long, default_value_t = 2048)]
+    pub(crate) cache_max_size_mb: u64,
+    /// Resume from this stage using the artifacts recorded in output/manifest.json
+    #[arg(long, value_enum, default_value_t = Stage::Capture)]
+    pub(crate) from_stage: Stage,
+    /// Speak and mix this commentary instead of asking the model
+    #[arg(long, conflicts_with_all = ["comment_audio", "from_stage"])]
+    pub(crate) comment_text: Option<PathBuf>,
+    /// Mix this commentary audio without calling the model or TTS
+    #[arg(long, conflicts_with = "from_stage")]
+    pub(crate) comment_audio: Option<PathBuf>,
+    #[arg(long, default_value_t = 500)]
+    pub(crate) capture_interval_msec: i64,
+    /// Send the contact sheets of the captured frames to the model instead of every frame
+    #[arg(long)]
+    pub(crate) contact_sheets: bool,
+    /// Frames per row of a contact sheet
+    #[arg(long, default_value_t = 4)]
+    pub(crate) sheet_columns: u32,
+    /// Rows of frames per contact sheet
+    #[arg(long, default_value_t = 4)]
+    pub(crate) sheet_rows: u32,
+    /// Volume of the original audio under the commentary
+    #[arg(long, default_value_t = 0.8)]
+    pub(crate) source_volume: f64,
+    #[arg(long, default_value_t = 1.2)]
+    pub(crate) commentary_volume: f64,
+    /// Container of the annotated video; hls and dash write a folder of segments
+    #[arg(long, value_enum, default_value_t = video::OutputFormat::Mp4)]
+    pub(crate) format: video::OutputFormat,
+    /// Video codec [default: vp9 for webm, h264 otherwise]
+    #[arg(long, value_enum)]
+    pub(crate) video_codec: Option<video::VideoCodec>,
+    /// Audio codec [default: opus for webm, aac otherwise]
+    #[arg(long, value_enum)]
+    pub(crate) audio_codec: Option<video::AudioCodec>,
+    /// Target length of the HLS/DASH segments
+    #[arg(long, default_value_t = 6)]
+    pub(crate) segment_sec: u32,
+    #[arg(long, value_enum, default_value_t = video::HlsSegments::Fmp4)]
+    pub(crate) hls_segments: video::HlsSegments,
+    /// Scaled HLS/DASH video renditions as HEIGHT:KBITS, e.g. 1080:5000,720:2800 [default: source size only]
+    #[arg(long, value_delimiter = ',')]
+    pub(crate) renditions: Vec<video::Rendition>,
+    /// Video stream to capture and annotate, counted from 0 among the video streams [default: the best one]
+    #[arg(long)]
+    pub(crate) video_stream: Option<usize>,
+    /// Audio stream to mix the commentary into, counted from 0 among the audio streams [default: the best one]
+    #[arg(long, conflicts_with = "audio_language")]
+    pub(crate) audio_stream: Option<usize>,
+    /// Pick the audio stream in this language, e.g. en or jpn
+    #[arg(long, value_parser = language::parse_tag)]
+    pub(crate) audio_language: Option<String>,
+    /// Copy the other audio and video streams into the output untouched instead of dropping them
+    #[arg(long)]
+    pub(crate) keep_streams: bool,
+    /// Streams other than audio and video, and chapters, to copy into the output
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "subtitles,chapters"
+    )]
+    pub(crate) passthrough: Vec<video::Passthrough>,
+    /// Subtitle streams to pass through, counted from 0 among the subtitle streams [default: all]
+    #[arg(long, value_delimiter = ',')]
+    pub(crate) subtitle_streams: Vec<usize>,
+    /// Pass through the subtitle streams in these languages, e.g. en,jpn [default: all]
+    #[arg(long, value_delimiter = ',', value_parser = language::parse_tag)]
+    pub(crate) subtitle_languages: Vec<String>,
+    /// Mark a chapter at every commentary segment, titled after it, instead of copying the source's
+    #[arg(long)]
+    pub(crate) commentary_chapters: bool,
+    /// What to do with HDR sources in the annotated video; captured frames are always tone mapped
+    #[arg(long, value_enum, default_value_t = video::HdrOutput::ToneMap)]
+    pub(crate) hdr_output: video::HdrOutput,
+    /// Turn image input into a slideshow video with the narration mixed in
+    #[arg(long)]
+    pub(crate) slideshow: bool,
+    /// How long each input image is shown and narrated
+    #[arg(long, default_value_t = 3)]
+    pub(crate) image_sec: i64,
+}
+
+impl Options {
+    pub(crate) fn prompt_source(&self) -> prompt::PromptSource<'_> {
+        self.commentary.prompt_source(
+            self.audio_description
+                .then_some(prompt::Preset::AudioDescription),
+        )
+    }
+}
+
+async fn annotate(
+    input_file: &Path,
+    options: &Options,
+    manifest: &Manifest,
+    language: &Language,
+    slots: &[video::Gap],
+    refinements: &[ai::Refinement],
+    cache: &Cache,
+) -> anyhow::Result<(prompt::Prompt, Vec<String>)> {
+    let frame_count = manifest
+        .capture
+        .as_ref()
+        .map_or(0, |capture| capture.frames.len());
+    let frames = if options.contact_sheets {
+        manifest.contact_sheets()?
+    } else {
+        manifest.captured_frames()?
+    };
+    let mut prompt = options.prompt_source().render(&prompt::PromptVars {
+        duration_sec: options.duration_sec,
+        start_sec: options.start_sec,
+        frame_count,
+        filename: &input_file.file_name().unwrap_or_default().to_string_lossy(),
+        language: &language.name,
+    })?;
+    if let Some(transcript) = manifest
+        .transcript
+        .as_ref()
+        .filter(|transcript| !transcript.segments.is_empty())
+    {
+        prompt
+            .user
+            .push_str(&transcript::prompt_section(&transcript.segments));
+    }
+    if options.contact_sheets {
+        prompt
+            .user
+            .push_str(&contact_sheet::prompt_section(frames.len(), frame_count));
+    }
+    if manifest.stills {
+        prompt
+            .user
+            .push_str(&stills_prompt_section(&manifest.clips));
+    } else if manifest.clips.len() > 1 {
+        prompt.user.push_str(&clips_prompt_section(&manifest.clips));
+    }
+    let comments = if options.audio_description || slots.len() > 1 {
+        ai::annotation_segments(
+            &prompt,
+            frames,
+            slots,
+            refinements,
+            &options.commentary.ai,
+            cache,
+        )
+        .await?
+    } else {
+        vec![
+            ai::annotation_frames(
+                &prompt,
+                frames.into_iter().map(|frame| frame.base64).collect(),
+                refinements,
+                &options.commentary.ai,
+                cache,
+            )
+            .await?,
+        ]
+    };
+
+    for comment in &comments {
+        println!("AI Comment ({}): {}", language.tag, comment);
+    }
+
+    Ok((prompt, comments))
+}
+
+fn clips_prompt_section(clips: &[JoinedClip]) -> String {
+    let clip_list = clips
+        .iter()
+        .enumerate()
+        .map(|(index, clip)| {
+            format!(
+                "{}. {} from {:.1}s to {:.1}s",
+                index + 1,
+                clip.path.file_name().unwrap_or_default().to_string_lossy(),
+                clip.offset_msec as f64 / 1000.0,
+                (clip.offset_msec + clip.duration_msec) as f64 / 1000.0
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!(
+        "\n\nThe video joins {} separate clips, one after the other:\n{}",
+        clips.len(),
+        clip_list
+    )
+}
+
+fn stills_prompt_section(images: &[JoinedClip]) -> String {
+    let image_list = images
+        .iter()
+        .enumerate()
+        .map(|(index, image)| {
+            format!(
+                "{}. {} (frame at {:.1}s)",
+                index + 1,
+                image.path.file_name().unwrap_or_default().to_string_lossy(),
+                image.offset_msec as f64 / 1000.0
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!(
+        "\n\nThe frames are {} separate still images rather than a video, \
+         each narrated for {:.1} seconds:\n{}",
+        images.len(),
+        images.first().map_or(0, |image| image.duration_msec) as f64 / 1000.0,
+        image_list
+    )
+}
+
+fn ask_feedback(slots: &[video::Gap], comments: &[String]) -> anyhow::Result<Option<String>> {
+    println!();
+    for (slot, comment) in slots.iter().zip(comments) {
+        println!(
+            "[{:.1}s - {:.1}s] {}",
+            slot.start_msec as f64 / 1000.0,
+            slot.end_msec as f64 / 1000.0,
+            comment
+        );
+    }
+    print!("Request changes, or press Enter to accept: ");
+    io::stdout().flush()?;
+    let mut feedback = String::new();
+    io::stdin().read_line(&mut feedback)?;
+    let feedback = feedback.trim();
+    Ok((!feedback.is_empty()).then(|| feedback.to_owned()))
+}
+
+pub(crate) async fn run(
+    input_file: &Path,
+    options: &Options,
+    output_dir: &Path,
+    cache: &Cache,
+    on_stage: &(dyn Fn(Stage) + Sync),
+) -> anyhow::Result<Manifest> {
+    if let Some(images) = video::image_files(input_file)? {
+        return run_images(input_file, &images, options, output_dir, cache, on_stage).await;
+    }
+    run_joined(input_file, &[], false, options, output_dir, cache, on_stage).await
+}
+
+/// Narrates the images one after the other, and shows them as a slideshow under the
+/// narration when asked to.
+async fn run_images(
+    input_file: &Path,
+    images: &[PathBuf],
+    options: &Options,
+    output_dir: &Path,
+    cache: &Cache,
+    on_stage: &(dyn Fn(Stage) + Sync),
+) -> anyhow::Result<Manifest> {
+    if options.transcribe.is_some() || options.audio_description {
+        anyhow::bail!("Still images have no audio to transcribe or describe around");
+    }
+    if options.image_sec <= 0 {
+        anyhow::bail!("Invalid image duration: {}s", options.image_sec);
+    }
+    fs::create_dir_all(output_dir)?;
+    let image_msec = options.image_sec * 1000;
+    let stills: Vec<JoinedClip> = images
+        .iter()
+        .enumerate()
+        .map(|(index, path)| JoinedClip {
+            path: path.clone(),
+            start_sec: 0,
+            offset_msec: index as i64 * image_msec,
+            duration_msec: image_msec,
+        })
+        .collect();
+
+    let input_file = if options.slideshow {
+        let slideshow_path = output_dir.join("slideshow.mp4");
+        if options.from_stage <= Stage::Capture {
+            video::init();
+            on_stage(Stage::Capture);
+            video::slideshow(images, image_msec, &slideshow_path)?;
+        }
+        slideshow_path
+    } else {
+        input_file.to_owned()
+    };
+    let mut options = options.clone();
+    options.start_sec = 0;
+    options.duration_sec = images.len() as i64 * options.image_sec;
+    run_joined(
+        &input_file,
+        &stills,
+        true,
+        &options,
+        output_dir,
+        cache,
+        on_stage,
+    )
+    .await
+}
+
+/// Joins the clips into one video and annotates it with one commentary per clip.
+pub(crate) async fn run_clips(
+    clips: &[video::Clip],
+    options: &Options,
+    output_dir: &Path,
+    cache: &Cache,
+    on_stage: &(dyn Fn(Stage) + Sync),
+) -> anyhow::Result<Manifest> {
+    fs::create_dir_all(output_dir)?;
+    let joined_path = output_dir.join("joined.mp4");
+    let joined_clips = if options.from_stage > Stage::Capture {
+        Manifest::load(&output_dir.join("manifest.json"))?.clips
+    } else {
+        video::init();
+        on_stage(Stage::Capture);
+        let clip_msecs = video::concat(clips, &joined_path)?;
+        let mut offset_msec = 0;
+        let mut joined_clips = Vec::new();
+        for (clip, duration_msec) in clips.iter().zip(clip_msecs) {
+            joined_clips.push(JoinedClip {
+                path: clip.path.clone(),
+                start_sec: clip.range.map_or(0, |(start_sec, _)| start_sec),
+                offset_msec,
+                duration_msec,
+            });
+            offset_msec += duration_msec;
+        }
+        joined_clips
+    };
+
+    let mut options = options.clone();
+    options.start_sec = 0;
+    let joined_msec: i64 = joined_clips.iter().map(|clip| clip.duration_msec).sum();
+    options.duration_sec = (joined_msec + 999) / 1000;
+    run_joined(
+        &joined_path,
+        &joined_clips,
+        false,
+        &options,
+        output_dir,
+        cache,
+        on_stage,
+    )
+    .await
+}
+
+/// The ISO 639-2 code that streams are tagged with, or the tag itself for other languages.
+fn stream_language(tag: &str) -> anyhow::Result<String> {
+    let language = Language::new(tag)?;
+    Ok(language.iso639_2.map_or(language.tag, str::to_owned))
+}
+
+async fn run_joined(
+    input_file: &Path,
+    clips: &[JoinedClip],
+    stills: bool,
+    options: &Options,
+    output_dir: &Path,
+    cache: &Cache,
+    on_stage: &(dyn Fn(Stage) + Sync),
+) -> anyhow::Result<Manifest> {
+    fs::exists(input_file)?;
+    fs::create_dir_all(output_dir)?;
+
+    if options.duration_sec <= 0 {
+        anyhow::bail!("Invalid duration: {}s", options.duration_sec);
+    }
+    if options.capture_interval_msec <= 0 {
+        anyhow::bail!(
+            "Invalid capture interval: {} ms",
+            options.capture_interval_msec
+        );
+    }
+    if options.min_tempo <= 0.0 || options.min_tempo > options.max_tempo {
+        anyhow::bail!(
+            "Invalid tempo range: {}..{}",
+            options.min_tempo,
+            options.max_tempo
+        );
+    }
+    let (min_speed, max_speed) = speech::SPEED_RANGE;
+    if options.speech_fit == speech::SpeechFit::Speed
+        && (options.min_tempo < min_speed || options.max_tempo > max_speed)
+    {
+        anyhow::bail!(
+            "The tempo range {}..{} goes beyond the TTS speeds {}..{}",
+            options.min_tempo,
+            options.max_tempo,
+            min_speed,
+            max_speed
+        );
+    }
+    let tempo_range = video::TempoRange {
+        min: options.min_tempo,
+        max: options.max_tempo,
+    };
+    let mut encoding = video::Encoding {
+        format: options.format,
+        video_codec: options.video_codec,
+        audio_codec: options.audio_codec,
+        segment_sec: options.segment_sec,
+        hls_segments: options.hls_segments,
+        renditions: options.renditions.clone(),
+        hdr_output: options.hdr_output,
+        streams: video::StreamSelection {
+            video: options.video_stream,
+            audio: options.audio_stream,
+            audio_language: options
+                .audio_language
+                .as_deref()
+                .map(stream_language)
+                .transpose()?,
+            keep_others: options.keep_streams,
+            passthrough: options.passthrough.clone(),
+            subtitles: options.subtitle_streams.clone(),
+            subtitle_languages: options
+                .subtitle_languages
+                .iter()
+                .map(|tag| stream_language(tag))
+                .collect::<anyhow::Result<_>>()?,
+        },
+        chapters: None,
+    };
+    encoding.validate()?;
+    let languages = options
+        .commentary
+        .languages
+        .iter()
+        .map(|tag| Language::new(tag))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let primary_language = languages
+        .first()
+        .ok_or(anyhow::anyhow!("At least one language is required"))?;
+
+    let manifest_path = &output_dir.join("manifest.json");
+    let from_stage = if options.comment_audio.is_some() {
+        Stage::Mix
+    } else if options.comment_text.is_some() {
+        Stage::Annotate
+    } else {
+        options.from_stage
+    };
+    let resumed = if from_stage > Stage::Capture {
+        let manifest = Manifest::load(manifest_path).and_then(|manifest| {
+            manifest.check_input(input_file, options.start_sec, options.duration_sec)?;
+            Ok(manifest)
+        });
+        match manifest {
+            Ok(manifest) => Some(manifest),
+            // Commentary given by the user needs nothing from an earlier run.
+            Err(_) if options.comment_text.is_some() || options.comment_audio.is_some() => None,
+            Err(e) => return Err(e),
+        }
+    } else {
+        None
+    };
+    let mut manifest = match resumed {
+        Some(manifest) => manifest,
+        None => {
+            let mut manifest = Manifest::new(input_file, options.start_sec, options.duration_sec);
+            manifest.clips = clips.to_vec();
+            manifest.stills = stills;
+            manifest
+        }
+    };
+    if let Some(comment_audio_path) = &options.comment_audio {
+        if !fs::exists(comment_audio_path)? {
+            anyhow::bail!("Comment audio not found: {}", comment_audio_path.display());
+        }
+        manifest.tts = Some(TtsStage {
+            tracks: vec![SpeechTrack {
+                language: primary_language.tag.clone(),
+                clips: vec![SpeechClip {
+                    start_msec: 0,
+                    window_msec: options.duration_sec * 1000,
+                    comment_path: None,
+                    spoken_comment: None,
+                    comment_audio_path: comment_audio_path.clone(),
+                    speed_fitted: false,
+                }],
+            }],
+        });
+    }
+
+    video::init();
+
+    if from_stage <= Stage::Capture {
+        on_stage(Stage::Capture);
+        let interval_msec = if manifest.stills {
+            options.image_sec * 1000
+        } else {
+            options.capture_interval_msec
+        };
+        let frames = if manifest.stills {
+            let images: Vec<PathBuf> = manifest
+                .clips
+                .iter()
+                .map(|image| image.path.clone())
+                .collect();
+            video::capture_images(&images, interval_msec, &output_dir.join("capture"))?
+        } else {
+            video::capture_base64(
+                input_file,
+                options.start_sec,
+                options.duration_sec,
+                options.capture_interval_msec,
+                &output_dir.join("capture"),
+                &encoding.streams,
+            )?
+        };
+
+        println!("Captured frames: {}", frames.len());
+        let sheets = contact_sheet::write_contact_sheets(
+            &frames,
+            options.sheet_columns,
+            options.sheet_rows,
+            &output_dir.join("capture"),
+        )?;
+
+        manifest.capture = Some(CaptureStage {
+            interval_msec,
+            frames: frames
+                .iter()
+                .map(|frame| CaptureFrame {
+                    path: frame.path.clone(),
+                    timestamp_msec: frame.timestamp_msec,
+                })
+                .collect(),
+            contact_sheets: sheets
+                .into_iter()
+                .map(|sheet| CaptureSheet {
+                    path: sheet.path,
+                    first_msec: sheet.first_msec,
+                    last_msec: sheet.last_msec,
+                    frame_count: sheet.frame_count,
+                })
+                .collect(),
+        });
+
+        manifest.save(manifest_path)?;
+    }
+
+    if from_stage <= Stage::Annotate {
+        on_stage(Stage::Annotate);
+        // Transcribed here rather than at capture, so that annotating again picks up a change
+        // of --transcribe.
+        let transcribed_with = manifest
+            .transcript
+            .as_ref()
+            .and_then(|transcript| transcript.transcriber);
+        // The model is not asked for commentary given by the user, so it needs no transcript.
+        if options.comment_text.is_none()
+            && (from_stage <= Stage::Capture || transcribed_with != options.transcribe)
+        {
+            manifest.transcript = match options.transcribe {
+                Some(transcriber) => {
+                    let audio_path = output_dir.join("transcript.wav");
+                    video::extract_speech_wav(
+                        input_file,
+                        options.start_sec,
+                        options.duration_sec,
+                        &audio_path,
+                        &encoding.streams,
+                    )?;
+                    let segments = transcript::transcribe(
+                        &audio_path,
+                        transcriber,
+                        &transcript::LocalWhisper {
+                            command: &options.whisper_command,
+                            model: options.whisper_model.as_deref(),
+                        },
+                        &options.commentary.ai,
+                        cache,
+                    )
+                    .await?;
+                    for segment in &segments {
+                        println!(
+                            "Transcript: {} ms - {} ms: {}",
+                            segment.start_msec, segment.end_msec, segment.text
+                        );
+                    }
+                    Some(TranscriptStage {
+                        transcriber: Some(transcriber),
+                        audio_path,
+                        segments,
+                    })
+                }
+                None => None,
+            };
+            manifest.save(manifest_path)?;
+        }
+
+        let range_msec = options.duration_sec * 1000;
+        let slots = if manifest.clips.len() > 1 {
+            manifest
+                .clips
+                .iter()
+                .map(|clip| video::Gap {
+                    start_msec: clip.offset_msec,
+                    end_msec: clip.offset_msec + clip.duration_msec,
+                })
+                .collect()
+        } else if options.audio_description {
+            let gaps = video::detect_quiet_gaps(
+                input_file,
+                options.start_sec,
+                options.duration_sec,
+                options.silence_db,
+                options.min_gap_msec,
+                &encoding.streams,
+            )?;
+            for gap in &gaps {
+                println!("Quiet gap: {} ms - {} ms", gap.start_msec, gap.end_msec);
+            }
+            if gaps.is_empty() {
+                anyhow::bail!(
+                    "No quiet gaps of at least {} ms to narrate in",
+                    options.min_gap_msec
+                );
+            }
+            gaps
+        } else {
+            vec![video::Gap {
+                start_msec: 0,
+                end_msec: range_msec,
+            }]
+        };
+
+        let (prompt, primary_comments) = match &options.comment_text {
+            Some(comment_path) => (None, vec![fs::read_to_string(comment_path)?]),
+            None => {
+                let (prompt, mut comments) = annotate(
+                    input_file,
+                    options,
+                    &manifest,
+                    primary_language,
+                    &slots,
+                    &[],
+                    cache,
+                )
+                .await?;
+                if options.interactive {
+                    let mut refinements = Vec::new();
+                    while let Some(feedback) = ask_feedback(&slots, &comments)? {
+                        refinements.push(ai::Refinement { comments, feedback });
+                        comments = annotate(
+                            input_file,
+                            options,
+                            &manifest,
+                            primary_language,
+                            &slots,
+                            &refinements,
+                            cache,
+                        )
+                        .await?
+                        .1;
+                    }
+                }
+                (Some(prompt), comments)
+            }
+        };
+
+        let mut comments = Vec::new();
+        for language in &languages {
+            let language_comments = if language.tag == primary_language.tag {
+                primary_comments.clone()
+            } else if options.annotate_each_language && options.comment_text.is_none() {
+                annotate(input_file, options, &manifest, language, &slots, &[], cache)
+                    .await?
+                    .1
+            } else {
+                let mut translated = Vec::new();
+                for comment in &primary_comments {
+                    let comment = ai::translate_comment(
+                        comment,
+                        &language.name,
+                        &options.commentary.ai,
+                        cache,
+                    )
+                    .await?;
+                    println!("Translated AI Comment ({}): {}", language.tag, comment);
+                    translated.push(comment);
+                }
+                translated
+            };
+
+            let mut segments = Vec::new();
+            for (index, (slot, comment)) in slots.iter().zip(language_comments).enumerate() {
+                let comment_path =
+                    output_dir.join(format!("comment.{}.{:02}.txt", language.tag, index));
+                fs::write(&comment_path, &comment)?;
+                segments.push(CommentSegment {
+                    start_msec: slot.start_msec,
+                    window_msec: slot.end_msec - slot.start_msec,
+                    comment_path,
+                });
+            }
+            comments.push(LanguageComment {
+                language: language.tag.clone(),
+                segments,
+            });
+        }
+
+        let (system_prompt, prompt) = match prompt {
+            Some(prompt) => (prompt.system, Some(prompt.user)),
+            None => (None, None),
+        };
+        manifest.annotate = Some(AnnotateStage {
+            system_prompt,
+            prompt,
+            comments,
+        });
+        manifest.save(manifest_path)?;
+    }
+
+    if from_stage <= Stage::Tts {
+        on_stage(Stage::Tts);
+        let comments = &manifest
+            .annotate
+            .as_ref()
+            .ok_or(anyhow::anyhow!("No annotate stage in run manifest"))?
+            .comments;
+        let mut tracks = Vec::new();
+        for comment in comments {
+            let mut clips = Vec::new();
+            for (index, segment) in comment.segments.iter().enumerate() {
+                let comment_audio_path =
+                    output_dir.join(format!("comment.{}.{:02}.mp3", comment.language, index));
+                let speech = speech::synthesize(
+                    &fs::read_to_string(&segment.comment_path)?,
+                    &comment_audio_path,
+                    segment.window_msec,
+                    speech::Fitting {
+                        fit: options.speech_fit,
+                        tempo_range,
+                        shorten_attempts: options.shorten_attempts,
+                    },
+                    &options.commentary.ai,
+                    cache,
+                )
+                .await?;
+                clips.push(SpeechClip {
+                    start_msec: segment.start_msec,
+                    window_msec: segment.window_msec,
+                    comment_path: Some(segment.comment_path.clone()),
+                    spoken_comment: Some(speech.comment),
+                    comment_audio_path,
+                    speed_fitted: speech.speed_fitted,
+                });
+            }
+            tracks.push(SpeechTrack {
+                language: comment.language.clone(),
+                clips,
+            });
+        }
+
+        manifest.tts = Some(TtsStage { tracks });
+        manifest.save(manifest_path)?;
+    }
+    if manifest.stills && !options.slideshow {
+        // Without a slideshow there is no video to mix the narration into.
+        return Ok(manifest);
+    }
+
+    on_stage(Stage::Mix);
+    let tracks = &manifest
+        .tts
+        .as_ref()
+        .ok_or(anyhow::anyhow!("No tts stage in run manifest"))?
+        .tracks;
+    let mut subtitle_paths = Vec::new();
+    for track in tracks {
+        let mut cues = Vec::new();
+        for clip in &track.clips {
+            let Some(spoken_comment) = &clip.spoken_comment else {
+                continue;
+            };
+            let mut spoken_msec = options.length_policy.spoken_msec(
+                clip.window_msec,
+                video::duration_msec(&clip.comment_audio_path)?,
+                if clip.speed_fitted {
+                    video::TempoRange::UNCHANGED
+                } else {
+                    tempo_range
+                },
+            );
+            if options.audio_description {
+                // The mixer cuts audio description at the end of its gap.
+                spoken_msec = spoken_msec.min(clip.window_msec);
+            }
+            cues.extend(subtitle::split_cues(
+                spoken_comment,
+                clip.start_msec,
+                clip.start_msec + spoken_msec,
+            ));
+        }
+        if cues.is_empty() {
+            continue;
+        }
+        let subtitle_path = output_dir.join(format!("comment.{}.srt", track.language));
+        subtitle::write_srt(&subtitle_path, &cues)?;
+        subtitle_paths.push(subtitle_path);
+    }
+
+    let commentary_tracks = tracks
+        .iter()
+        .map(|track| {
+            Ok(video::CommentaryTrack {
+                clips: track
+                    .clips
+                    .iter()
+                    .map(|clip| video::CommentaryClip {
+                        audio_path: clip.comment_audio_path.clone(),
+                        start_msec: clip.start_msec,
+                        window_msec: clip.window_msec,
+                        speed_fitted: clip.speed_fitted,
+                    })
+                    .collect(),
+                language: Language::new(&track.language)?.iso639_2.map(str::to_owned),
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if options.commentary_chapters {
+        let segments: Vec<(i64, &str)> = tracks
+            .first()
+            .into_iter()
+            .flat_map(|track| &track.clips)
+            .filter_map(|clip| Some((clip.start_msec, clip.spoken_comment.as_deref()?)))
+            .collect();
+        // The last chapter runs to the end of the output, which transcode cuts it at.
+        encoding.chapters = Some(chapter::from_segments(&segments, i64::MAX));
+    }
+    let transcoded_path = options.format.output_path(output_dir);
+    let chapters = video::transcode(
+        input_file,
+        &commentary_tracks,
+        &transcoded_path,
+        options.start_sec,
+        options.duration_sec,
+        video::Mix {
+            length_policy: options.length_policy,
+            tempo_range,
+            source_volume: options.source_volume,
+            commentary_volume: options.commentary_volume,
+            trim_to_windows: options.audio_description,
+        },
+        &encoding,
+    )?;
+    let chapters_path = if chapters.is_empty() {
+        None
+    } else {
+        let chapters_path = output_dir.join("chapters.txt");
+        chapter::write_ffmetadata(&chapters_path, &chapters)?;
+        Some(chapters_path)
+    };
+
+    manifest.mix = Some(MixStage {
+        comment_audio_paths: commentary_tracks
+            .into_iter()
+            .flat_map(|track| track.clips)
+            .map(|clip| clip.audio_path)
+            .collect(),
+        subtitle_paths,
+        chapters_path,
+        output_path: transcoded_path,
+    });
+    manifest.save(manifest_path)?;
+
+    Ok(manifest)
+}
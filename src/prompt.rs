@@ -0,0 +1,231 @@
+use std::fs;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Preset {
+    /// Lively play-by-play commentary
+    Sports,
+    /// Accessibility audio description for blind and low-vision viewers
+    AudioDescription,
+    /// Security camera review
+    Security,
+}
+
+impl Preset {
+    fn system_prompt(&self) -> &'static str {
+        match self {
+            Preset::Sports => {
+                "You are an energetic sports commentator. You describe the action as it happens, \
+                 name visible players, teams and scores when they can be read, and build excitement \
+                 around key moments. Never invent facts that are not visible in the frames. \
+                 Always answer in {language}."
+            }
+            Preset::AudioDescription => {
+                "You write audio description for blind and low-vision viewers. You describe \
+                 only what is visually important: people, actions, settings, on-screen text and \
+                 changes of scene, in the present tense and in a neutral tone. You do not interpret \
+                 emotions beyond what is clearly shown and you do not describe sounds. \
+                 Always answer in {language}."
+            }
+            Preset::Security => {
+                "You are a security analyst reviewing surveillance footage. You report people, \
+                 vehicles and objects entering or leaving the scene, unusual or suspicious \
+                 behavior, and the approximate time of each event. Be factual and concise and \
+                 state clearly when nothing notable happens. Always answer in {language}."
+            }
+        }
+    }
+
+    fn user_prompt(&self) -> &'static str {
+        match self {
+            Preset::Sports => {
+                "These {frame_count} frames are taken from {filename}, starting at {start} seconds \
+                 and covering {duration} seconds. Give a play-by-play commentary of this clip \
+                 that can be spoken within {duration} seconds."
+            }
+            Preset::AudioDescription => {
+                "These {frame_count} frames are taken from {filename}, starting at {start} seconds \
+                 and covering {duration} seconds. Write an audio description of this clip \
+                 that can be spoken within {duration} seconds."
+            }
+            Preset::Security => {
+                "These {frame_count} frames are taken from {filename}, starting at {start} seconds \
+                 and covering {duration} seconds. Summarize the events in this footage \
+                 in a report that can be spoken within {duration} seconds."
+            }
+        }
+    }
+}
+
+pub(crate) struct PromptVars<'a> {
+    pub(crate) duration_sec: i64,
+    pub(crate) start_sec: i64,
+    pub(crate) frame_count: usize,
+    pub(crate) filename: &'a str,
+    pub(crate) language: &'a str,
+}
+
+pub(crate) struct Prompt {
+    pub(crate) system: Option<String>,
+    pub(crate) user: String,
+}
+
+pub(crate) struct PromptSource<'a> {
+    pub(crate) preset: Option<Preset>,
+    pub(crate) prompt: Option<&'a str>,
+    pub(crate) prompt_file: Option<&'a Path>,
+    pub(crate) system_prompt: Option<&'a str>,
+    pub(crate) system_prompt_file: Option<&'a Path>,
+}
+
+impl PromptSource<'_> {
+    /// Fills in the variables of prompt files and presets; prompts given on the command line
+    /// are used as written.
+    pub(crate) fn render(&self, vars: &PromptVars) -> anyhow::Result<Prompt> {
+        let user = match (self.prompt, self.prompt_file, self.preset) {
+            (Some(prompt), _, _) => prompt.to_owned(),
+            (None, Some(path), _) => render(&read_template(path)?, vars)?,
+            (None, None, Some(preset)) => render(preset.user_prompt(), vars)?,
+            (None, None, None) => {
+                anyhow::bail!("--prompt, --prompt-file or --preset is required to annotate")
+            }
+        };
+        let system = match (self.system_prompt, self.system_prompt_file, self.preset) {
+            (Some(prompt), _, _) => Some(prompt.to_owned()),
+            (None, Some(path), _) => Some(render(&read_template(path)?, vars)?),
+            (None, None, Some(preset)) => Some(render(preset.system_prompt(), vars)?),
+            (None, None, None) => None,
+        };
+
+        Ok(Prompt { system, user })
+    }
+}
+
+fn read_template(path: &Path) -> anyhow::Result<String> {
+    fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read prompt file {}: {}", path.display(), e))
+}
+
+fn render(template: &str, vars: &PromptVars) -> anyhow::Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut chars = template.char_indices().peekable();
+    while let Some((offset, c)) = chars.next() {
+        match c {
+            '{' if chars.next_if(|&(_, c)| c == '{').is_some() => rendered.push('{'),
+            '}' if chars.next_if(|&(_, c)| c == '}').is_some() => rendered.push('}'),
+            '{' => {
+                let mut name = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '}')) => break,
+                        Some((_, c)) => name.push(c),
+                        None => anyhow::bail!(
+                            "Unterminated prompt variable at offset {}: {{{}",
+                            offset,
+                            name
+                        ),
+                    }
+                }
+                match name.as_str() {
+                    "duration" => rendered.push_str(&vars.duration_sec.to_string()),
+                    "start" => rendered.push_str(&vars.start_sec.to_string()),
+                    "frame_count" => rendered.push_str(&vars.frame_count.to_string()),
+                    "filename" => rendered.push_str(vars.filename),
+                    "language" => rendered.push_str(vars.language),
+                    _ => anyhow::bail!("Unknown prompt variable: {{{}}}", name),
+                }
+            }
+            _ => rendered.push(c),
+        }
+    }
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::ValueEnum;
+
+    const VARS: PromptVars = PromptVars {
+        duration_sec: 30,
+        start_sec: 5,
+        frame_count: 60,
+        filename: "match.mp4",
+        language: "French",
+    };
+
+    #[test]
+    fn render_fills_in_variables() {
+        assert_eq!(
+            render(
+                "{frame_count} frames of {filename} from {start} s for {duration} s in {language}",
+                &VARS
+            )
+            .unwrap(),
+            "60 frames of match.mp4 from 5 s for 30 s in French"
+        );
+    }
+
+    #[test]
+    fn render_unescapes_doubled_braces() {
+        assert_eq!(
+            render("{{\"duration\": {duration}}} }}{{", &VARS).unwrap(),
+            "{\"duration\": 30} }{"
+        );
+    }
+
+    #[test]
+    fn render_rejects_unknown_variables() {
+        let error = render("Describe {scene}", &VARS).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown prompt variable: {scene}");
+    }
+
+    #[test]
+    fn render_rejects_unterminated_variables() {
+        let error = render("Speak in {language} for {duration", &VARS).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Unterminated prompt variable at offset 24: {duration"
+        );
+        assert!(render("Trailing {", &VARS).is_err());
+    }
+
+    #[test]
+    fn presets_are_looked_up_by_name() {
+        assert_eq!(
+            Preset::from_str("audio-description", false).unwrap(),
+            Preset::AudioDescription
+        );
+        assert!(Preset::from_str("news", false).is_err());
+
+        let prompt = PromptSource {
+            preset: Some(Preset::Sports),
+            prompt: None,
+            prompt_file: None,
+            system_prompt: None,
+            system_prompt_file: None,
+        }
+        .render(&VARS)
+        .unwrap();
+        assert!(prompt
+            .user
+            .starts_with("These 60 frames are taken from match.mp4"));
+        assert!(prompt.system.unwrap().ends_with("Always answer in French."));
+    }
+
+    #[test]
+    fn command_line_prompts_are_used_as_written() {
+        let prompt = PromptSource {
+            preset: Some(Preset::Security),
+            prompt: Some("Count the {cars}"),
+            prompt_file: None,
+            system_prompt: None,
+            system_prompt_file: None,
+        }
+        .render(&VARS)
+        .unwrap();
+        assert_eq!(prompt.user, "Count the {cars}");
+        assert!(prompt.system.is_some());
+    }
+}
@@ -0,0 +1,603 @@
+use axum::body::Body;
+use axum::extract::{DefaultBodyLimit, Multipart, Path as UrlPath, State};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use clap::{CommandFactory, FromArgMatches};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::manifest::Stage;
+use crate::pipeline;
+
+/// Options that name files or commands on the server, which clients must not choose.
+const SERVER_SIDE_OPTIONS: &[&str] = &[
+    "prompt_file",
+    "system_prompt_file",
+    "comment_text",
+    "comment_audio",
+    "whisper_command",
+    "whisper_model",
+];
+
+#[derive(clap::Args)]
+pub(crate) struct ServeArgs {
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    listen: SocketAddr,
+    /// Directory holding the job records, uploads and artifacts
+    #[arg(long, default_value = "jobs")]
+    jobs_dir: PathBuf,
+    /// Number of jobs processed at the same time
+    #[arg(long, default_value_t = 2)]
+    workers: usize,
+    #[arg(long, default_value_t = 1024)]
+    max_upload_mb: usize,
+    #[arg(long, default_value_t = 30)]
+    cache_max_age_days: u64,
+    #[arg(long, default_value_t = 2048)]
+    cache_max_size_mb: u64,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Job {
+    id: String,
+    status: JobStatus,
+    stage: Option<Stage>,
+    error: Option<String>,
+    input_file: PathBuf,
+    /// Pipeline options as posted, parsed again when the job runs
+    options: serde_json::Value,
+    artifacts: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct JobRequest {
+    input_file: PathBuf,
+    #[serde(flatten)]
+    options: serde_json::Map<String, serde_json::Value>,
+}
+
+struct JobStore {
+    dir: PathBuf,
+    jobs: Mutex<HashMap<String, Job>>,
+}
+
+impl JobStore {
+    fn open(dir: &Path) -> anyhow::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut jobs = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let job_path = entry?.path().join("job.json");
+            if !fs::exists(&job_path)? {
+                continue;
+            }
+            // One damaged job must not keep the server from serving the others.
+            match fs::read(&job_path)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| Ok(serde_json::from_slice::<Job>(&data)?))
+            {
+                Ok(job) => {
+                    jobs.insert(job.id.clone(), job);
+                }
+                Err(e) => eprintln!("Skipping job {}: {}", job_path.display(), e),
+            }
+        }
+        Ok(Self {
+            dir: dir.to_owned(),
+            jobs: Mutex::new(jobs),
+        })
+    }
+
+    fn job_dir(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+
+    fn new_id(&self) -> anyhow::Result<String> {
+        let jobs = self
+            .jobs
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Job store poisoned"))?;
+        let mut nanos = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_nanos();
+        let id = loop {
+            let id = format!("{:x}", nanos);
+            if !jobs.contains_key(&id) && !fs::exists(self.job_dir(&id))? {
+                break id;
+            }
+            nanos += 1;
+        };
+        Ok(id)
+    }
+
+    fn save(&self, job: &Job) -> anyhow::Result<()> {
+        let path = self.job_dir(&job.id).join("job.json");
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_vec_pretty(job)?)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    fn insert(&self, job: Job) -> anyhow::Result<()> {
+        self.save(&job)?;
+        self.jobs
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Job store poisoned"))?
+            .insert(job.id.clone(), job);
+        Ok(())
+    }
+
+    fn get(&self, id: &str) -> Option<Job> {
+        self.jobs.lock().ok()?.get(id).cloned()
+    }
+
+    fn list(&self) -> Vec<Job> {
+        let mut jobs: Vec<Job> = self
+            .jobs
+            .lock()
+            .map(|jobs| jobs.values().cloned().collect())
+            .unwrap_or_default();
+        jobs.sort_by(|a, b| a.id.cmp(&b.id));
+        jobs
+    }
+
+    fn update(&self, id: &str, change: impl FnOnce(&mut Job)) -> anyhow::Result<()> {
+        let mut jobs = self
+            .jobs
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Job store poisoned"))?;
+        let job = jobs
+            .get_mut(id)
+            .ok_or(anyhow::anyhow!("Unknown job: {}", id))?;
+        change(job);
+        self.save(job)
+    }
+}
+
+struct AppState {
+    store: JobStore,
+    /// The pipeline options with the configured defaults
+    options_command: clap::Command,
+    queue: mpsc::UnboundedSender<String>,
+}
+
+struct ApiError(StatusCode, String);
+
+impl<E: Into<anyhow::Error>> From<E> for ApiError {
+    fn from(error: E) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, error.into().to_string())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response()
+    }
+}
+
+pub(crate) async fn serve(args: ServeArgs, config: &Config) -> anyhow::Result<()> {
+    let options_command = config.apply_known(pipeline::Options::command())?;
+    let cache = Cache::new(args.jobs_dir.join("cache"));
+    let pruned = cache.prune(
+        Duration::from_secs(args.cache_max_age_days * 24 * 60 * 60),
+        args.cache_max_size_mb * 1024 * 1024,
+    )?;
+    if pruned > 0 {
+        println!("Pruned cache entries: {}", pruned);
+    }
+
+    let store = JobStore::open(&args.jobs_dir)?;
+    let (queue, receiver) = mpsc::unbounded_channel();
+    // Jobs interrupted by a restart run again from the beginning.
+    for job in store.list() {
+        if matches!(job.status, JobStatus::Queued | JobStatus::Running) {
+            store.update(&job.id, |job| {
+                job.status = JobStatus::Queued;
+                job.stage = None;
+            })?;
+            queue.send(job.id)?;
+        }
+    }
+    let state = Arc::new(AppState {
+        store,
+        options_command,
+        queue,
+    });
+
+    let receiver = Arc::new(tokio::sync::Mutex::new(receiver));
+    for _ in 0..args.workers.max(1) {
+        tokio::spawn(worker(state.clone(), receiver.clone()));
+    }
+
+    let router = Router::new()
+        .route("/jobs", post(create_job).get(list_jobs))
+        .route("/jobs/upload", post(upload_job))
+        .route("/jobs/{id}", get(show_job))
+        .route("/jobs/{id}/artifacts/{*name}", get(download_artifact))
+        .layer(DefaultBodyLimit::max(args.max_upload_mb * 1024 * 1024))
+        .with_state(state);
+
+    let listener = tokio::net::TcpListener::bind(args.listen).await?;
+    println!("Listening on http://{}", listener.local_addr()?);
+    axum::serve(listener, router).await?;
+    Ok(())
+}
+
+fn option_value(key: &str, value: &serde_json::Value) -> anyhow::Result<String> {
+    match value {
+        serde_json::Value::String(value) => Ok(value.clone()),
+        serde_json::Value::Number(value) => Ok(value.to_string()),
+        serde_json::Value::Bool(value) => Ok(value.to_string()),
+        _ => anyhow::bail!("Unsupported value for {}", key),
+    }
+}
+
+/// Parses the job options as their command-line flags over the configured defaults, so that
+/// they are checked like the command line, conflicts included.
+fn job_options(
+    command: &clap::Command,
+    options: &serde_json::Value,
+) -> anyhow::Result<pipeline::Options> {
+    let options = options
+        .as_object()
+        .ok_or(anyhow::anyhow!("Job options must be an object"))?;
+    let mut args = vec!["annotai".to_owned()];
+    for (key, value) in options {
+        let key = key.replace('-', "_");
+        if SERVER_SIDE_OPTIONS.contains(&key.as_str()) {
+            anyhow::bail!("{} cannot be set on a job", key);
+        }
+        let flag = format!("--{}", key.replace('_', "-"));
+        match value {
+            serde_json::Value::Null | serde_json::Value::Bool(false) => {}
+            serde_json::Value::Bool(true) => args.push(flag),
+            serde_json::Value::Array(values) => {
+                for value in values {
+                    args.push(format!("{}={}", flag, option_value(&key, value)?));
+                }
+            }
+            value => args.push(format!("{}={}", flag, option_value(&key, value)?)),
+        }
+    }
+    Ok(pipeline::Options::from_arg_matches(
+        &command.clone().try_get_matches_from(args)?,
+    )?)
+}
+
+fn enqueue(
+    state: &AppState,
+    id: String,
+    input_file: PathBuf,
+    options: serde_json::Map<String, serde_json::Value>,
+) -> Result<Json<Job>, ApiError> {
+    let options = serde_json::Value::Object(options);
+    // Reject bad options now rather than when a worker picks the job up.
+    job_options(&state.options_command, &options)
+        .map_err(|e| ApiError(StatusCode::BAD_REQUEST, format!("{:#}", e)))?;
+    fs::create_dir_all(state.store.job_dir(&id))?;
+    let job = Job {
+        id: id.clone(),
+        status: JobStatus::Queued,
+        stage: None,
+        error: None,
+        input_file,
+        options,
+        artifacts: Vec::new(),
+    };
+    state.store.insert(job.clone())?;
+    state.queue.send(id)?;
+    Ok(Json(job))
+}
+
+async fn create_job(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<JobRequest>,
+) -> Result<Json<Job>, ApiError> {
+    if !fs::exists(&request.input_file)? {
+        return Err(ApiError(
+            StatusCode::BAD_REQUEST,
+            format!("Input file not found: {}", request.input_file.display()),
+        ));
+    }
+    let id = state.store.new_id()?;
+    enqueue(&state, id, request.input_file, request.options)
+}
+
+async fn upload_job(
+    State(state): State<Arc<AppState>>,
+    mut multipart: Multipart,
+) -> Result<Json<Job>, ApiError> {
+    let id = state.store.new_id()?;
+    // Kept next to the job folders until the options are known to be valid.
+    let upload_path = state.store.dir.join(format!("{}.upload", id));
+    let result = receive_upload(&mut multipart, &upload_path).await;
+    let (extension, options) = match result {
+        Ok(upload) => upload,
+        Err(e) => {
+            fs::remove_file(&upload_path).ok();
+            return Err(e);
+        }
+    };
+    if let Err(e) = job_options(
+        &state.options_command,
+        &serde_json::Value::Object(options.clone()),
+    ) {
+        fs::remove_file(&upload_path).ok();
+        return Err(ApiError(StatusCode::BAD_REQUEST, format!("{:#}", e)));
+    }
+    fs::create_dir_all(state.store.job_dir(&id))?;
+    let input_file = state
+        .store
+        .job_dir(&id)
+        .join(format!("input.{}", extension));
+    fs::rename(&upload_path, &input_file)?;
+    enqueue(&state, id, input_file, options)
+}
+
+/// Writes the file field to `upload_path` and returns its extension with the job options.
+async fn receive_upload(
+    multipart: &mut Multipart,
+    upload_path: &Path,
+) -> Result<(String, serde_json::Map<String, serde_json::Value>), ApiError> {
+    let mut extension = None;
+    let mut options = serde_json::Map::new();
+    while let Some(mut field) = multipart.next_field().await? {
+        match field.name() {
+            Some("file") => {
+                extension = Some(
+                    field
+                        .file_name()
+                        .and_then(|name| Path::new(name).extension())
+                        .map(|extension| extension.to_string_lossy().into_owned())
+                        .unwrap_or_else(|| "mp4".to_owned()),
+                );
+                let mut file = fs::File::create(upload_path)?;
+                while let Some(chunk) = field.chunk().await? {
+                    file.write_all(&chunk)?;
+                }
+            }
+            Some("options") => {
+                options = serde_json::from_str(&field.text().await?)
+                    .map_err(|e| ApiError(StatusCode::BAD_REQUEST, e.to_string()))?;
+            }
+            _ => {}
+        }
+    }
+    let extension = extension.ok_or(ApiError(
+        StatusCode::BAD_REQUEST,
+        "Missing file field".to_owned(),
+    ))?;
+    Ok((extension, options))
+}
+
+async fn list_jobs(State(state): State<Arc<AppState>>) -> Json<Vec<Job>> {
+    Json(state.store.list())
+}
+
+async fn show_job(
+    State(state): State<Arc<AppState>>,
+    UrlPath(id): UrlPath<String>,
+) -> Result<Json<Job>, ApiError> {
+    state.store.get(&id).map(Json).ok_or(ApiError(
+        StatusCode::NOT_FOUND,
+        format!("Unknown job: {}", id),
+    ))
+}
+
+async fn download_artifact(
+    State(state): State<Arc<AppState>>,
+    UrlPath((id, name)): UrlPath<(String, String)>,
+) -> Result<Response, ApiError> {
+    let job = state.store.get(&id).ok_or(ApiError(
+        StatusCode::NOT_FOUND,
+        format!("Unknown job: {}", id),
+    ))?;
+    // Only serve the files listed on the job, never arbitrary paths.
+    if !job.artifacts.contains(&name) {
+        return Err(ApiError(
+            StatusCode::NOT_FOUND,
+            format!("Unknown artifact: {}", name),
+        ));
+    }
+    let data = tokio::fs::read(state.store.job_dir(&id).join("output").join(&name)).await?;
+    let content_type = match Path::new(&name).extension().and_then(|e| e.to_str()) {
+        Some("mp4") => "video/mp4",
+        Some("mkv") => "video/x-matroska",
+        Some("webm") => "video/webm",
+        Some("mov") => "video/quicktime",
+        Some("ts") => "video/mp2t",
+        Some("m3u8") => "application/vnd.apple.mpegurl",
+        Some("mpd") => "application/dash+xml",
+        Some("m4s") => "video/iso.segment",
+        Some("mp3") => "audio/mpeg",
+        Some("srt") => "application/x-subrip",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    };
+    Ok(([(header::CONTENT_TYPE, content_type)], Body::from(data)).into_response())
+}
+
+async fn worker(
+    state: Arc<AppState>,
+    receiver: Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<String>>>,
+) {
+    loop {
+        let Some(id) = receiver.lock().await.recv().await else {
+            return;
+        };
+        println!("Job {} started", id);
+        let result = run_job(&state, &id).await;
+        let updated = state.store.update(&id, |job| match result {
+            Ok(artifacts) => {
+                job.status = JobStatus::Done;
+                job.artifacts = artifacts;
+            }
+            Err(e) => {
+                job.status = JobStatus::Failed;
+                job.error = Some(format!("{:#}", e));
+            }
+        });
+        match updated {
+            Ok(()) => println!("Job {} finished", id),
+            Err(e) => eprintln!("Job {} could not be updated: {}", id, e),
+        }
+    }
+}
+
+async fn run_job(state: &Arc<AppState>, id: &str) -> anyhow::Result<Vec<String>> {
+    let job = state
+        .store
+        .get(id)
+        .ok_or(anyhow::anyhow!("Unknown job: {}", id))?;
+    state
+        .store
+        .update(id, |job| job.status = JobStatus::Running)?;
+
+    let mut options = job_options(&state.options_command, &job.options)?;
+    // There is nobody at the terminal to answer.
+    options.interactive = false;
+    let output_dir = state.store.job_dir(id).join("output");
+    let cache = if options.no_cache {
+        Cache::disabled()
+    } else {
+        Cache::new(state.store.dir.join("cache"))
+    };
+
+    // The pipeline decodes and encodes synchronously, so keep it off the async workers.
+    let runtime = tokio::runtime::Handle::current();
+    let state = state.clone();
+    let job_id = id.to_owned();
+    let run_dir = output_dir.clone();
+    let manifest = tokio::task::spawn_blocking(move || {
+        runtime.block_on(pipeline::run(
+            &job.input_file,
+            &options,
+            &run_dir,
+            &cache,
+            &|stage| {
+                if let Err(e) = state.store.update(&job_id, |job| job.stage = Some(stage)) {
+                    eprintln!("Job {} could not be updated: {}", job_id, e);
+                }
+            },
+        ))
+    })
+    .await??;
+
+    let mut paths = vec![output_dir.join("manifest.json")];
+    match (manifest.mix, manifest.tts) {
+        (Some(mix), _) => {
+            match mix.output_path.parent() {
+                // Segmented outputs are a folder of playlists and segments.
+                Some(dir) if dir != output_dir => {
+                    for entry in fs::read_dir(dir)? {
+                        paths.push(entry?.path());
+                    }
+                }
+                _ => paths.push(mix.output_path),
+            }
+            paths.extend(mix.subtitle_paths);
+            paths.extend(mix.comment_audio_paths);
+        }
+        // Still images without a slideshow are narrated, but there is no video to mix into.
+        (None, Some(tts)) if manifest.stills => {
+            for clip in tts.tracks.into_iter().flat_map(|track| track.clips) {
+                paths.extend(clip.comment_path);
+                paths.push(clip.comment_audio_path);
+            }
+        }
+        _ => anyhow::bail!("No mix stage in run manifest"),
+    }
+    let artifacts = paths
+        .iter()
+        .filter_map(|path| path.strip_prefix(&output_dir).ok())
+        .map(|path| path.to_string_lossy().replace('\\', "/"))
+        .collect();
+    Ok(artifacts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(options: serde_json::Value) -> anyhow::Result<pipeline::Options> {
+        job_options(&pipeline::Options::command(), &options)
+    }
+
+    #[test]
+    fn job_options_are_parsed_like_flags() {
+        let options = parse(serde_json::json!({
+            "languages": ["en", "ja"],
+            "duration-sec": 10,
+            "slideshow": true,
+        }))
+        .unwrap();
+        assert_eq!(options.commentary.languages, ["en", "ja"]);
+        assert_eq!(options.duration_sec, 10);
+        assert!(options.slideshow);
+    }
+
+    #[test]
+    fn job_options_reject_unsafe_language_tags() {
+        for languages in [
+            serde_json::json!(["en", "../../etc"]),
+            serde_json::json!("en,x:amovie=y"),
+        ] {
+            assert!(parse(serde_json::json!({ "languages": languages })).is_err());
+        }
+        assert!(parse(serde_json::json!({ "audio_language": "en;" })).is_err());
+    }
+
+    #[test]
+    fn job_options_reject_server_side_options() {
+        let Err(error) = parse(serde_json::json!({ "prompt-file": "/etc/passwd" })) else {
+            panic!("prompt-file was accepted");
+        };
+        assert_eq!(error.to_string(), "prompt_file cannot be set on a job");
+    }
+
+    #[test]
+    fn job_store_skips_damaged_jobs() {
+        let dir = std::env::temp_dir().join(format!("annotai-jobs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = JobStore::open(&dir).unwrap();
+        fs::create_dir_all(store.job_dir("good")).unwrap();
+        store
+            .insert(Job {
+                id: "good".to_owned(),
+                status: JobStatus::Done,
+                stage: None,
+                error: None,
+                input_file: PathBuf::from("clip.mp4"),
+                options: serde_json::json!({}),
+                artifacts: Vec::new(),
+            })
+            .unwrap();
+        fs::create_dir_all(store.job_dir("bad")).unwrap();
+        fs::write(store.job_dir("bad").join("job.json"), "{\"id\":").unwrap();
+
+        let store = JobStore::open(&dir).unwrap();
+        assert!(store.get("good").is_some());
+        assert_eq!(store.list().len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,80 @@
+use std::path::Path;
+
+use crate::cache::Cache;
+use crate::{ai, video};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum SpeechFit {
+    /// Keep the speech as is and let the mixer speed it up within the tempo range
+    Tempo,
+    /// Re-synthesize the speech with a TTS speed within the tempo range
+    Speed,
+    /// Ask the model for a shorter commentary and re-synthesize it
+    Shorten,
+}
+
+/// Speeds the TTS can synthesize at.
+pub(crate) const SPEED_RANGE: (f64, f64) = (0.25, 4.0);
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Fitting {
+    pub(crate) fit: SpeechFit,
+    pub(crate) tempo_range: video::TempoRange,
+    pub(crate) shorten_attempts: u32,
+}
+
+pub(crate) struct Speech {
+    pub(crate) comment: String,
+    /// Synthesized at a speed other than 1, so the mixer must not change its tempo again
+    pub(crate) speed_fitted: bool,
+}
+
+pub(crate) async fn synthesize(
+    comment: &str,
+    output_path: &Path,
+    window_msec: i64,
+    fitting: Fitting,
+    settings: &ai::Settings,
+    cache: &Cache,
+) -> anyhow::Result<Speech> {
+    let mut comment = comment.to_owned();
+    let mut speed_fitted = false;
+    ai::audio_speech(&comment, output_path, 1.0, settings, cache).await?;
+    let mut speech_msec = video::duration_msec(output_path)?;
+    println!(
+        "Speech duration: {} ms (window: {} ms)",
+        speech_msec, window_msec
+    );
+
+    match fitting.fit {
+        SpeechFit::Tempo => {}
+        SpeechFit::Speed => {
+            let speed = fitting.tempo_range.fit(speech_msec, window_msec);
+            if speed != 1.0 {
+                ai::audio_speech(&comment, output_path, speed as f32, settings, cache).await?;
+                speech_msec = video::duration_msec(output_path)?;
+                println!("Speech duration at speed {:.2}: {} ms", speed, speech_msec);
+                speed_fitted = true;
+            }
+        }
+        SpeechFit::Shorten => {
+            for _ in 0..fitting.shorten_attempts {
+                if speech_msec <= window_msec {
+                    break;
+                }
+                comment = ai::shorten_comment(&comment, speech_msec, window_msec, settings, cache)
+                    .await?;
+                println!("Shortened AI Comment: {}", comment);
+                ai::audio_speech(&comment, output_path, 1.0, settings, cache).await?;
+                speech_msec = video::duration_msec(output_path)?;
+                println!("Speech duration: {} ms", speech_msec);
+            }
+        }
+    }
+
+    Ok(Speech {
+        comment,
+        speed_fitted,
+    })
+}
@@ -0,0 +1,253 @@
+use std::fs;
+use std::path::Path;
+
+pub(crate) struct Cue {
+    pub(crate) start_msec: i64,
+    pub(crate) end_msec: i64,
+    pub(crate) text: String,
+}
+
+pub(crate) fn split_cues(text: &str, start_msec: i64, end_msec: i64) -> Vec<Cue> {
+    let mut sentences = Vec::new();
+    let mut sentence = String::new();
+    for c in text.chars() {
+        sentence.push(c);
+        if matches!(c, '.' | '!' | '?' | '。' | '！' | '？') {
+            sentences.push(sentence.trim().to_owned());
+            sentence.clear();
+        }
+    }
+    sentences.push(sentence.trim().to_owned());
+    sentences.retain(|sentence| !sentence.is_empty());
+
+    // Spread the time over the sentences in proportion to their length.
+    let total_chars: usize = sentences.iter().map(|s| s.chars().count()).sum();
+    let span_msec = (end_msec - start_msec).max(0);
+    let mut chars = 0;
+    sentences
+        .into_iter()
+        .map(|sentence| {
+            let cue_start = start_msec + span_msec * chars as i64 / total_chars.max(1) as i64;
+            chars += sentence.chars().count();
+            let cue_end = start_msec + span_msec * chars as i64 / total_chars.max(1) as i64;
+            Cue {
+                start_msec: cue_start,
+                end_msec: cue_end,
+                text: sentence,
+            }
+        })
+        .collect()
+}
+
+fn srt_timestamp(msec: i64) -> String {
+    format!(
+        "{:02}:{:02}:{:02},{:03}",
+        msec / 3_600_000,
+        msec / 60_000 % 60,
+        msec / 1000 % 60,
+        msec % 1000
+    )
+}
+
+pub(crate) fn write_srt(path: &Path, cues: &[Cue]) -> anyhow::Result<()> {
+    let srt = cues
+        .iter()
+        .enumerate()
+        .map(|(index, cue)| {
+            format!(
+                "{}\n{} --> {}\n{}\n",
+                index + 1,
+                srt_timestamp(cue.start_msec),
+                srt_timestamp(cue.end_msec),
+                cue.text
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    fs::write(path, srt)?;
+    Ok(())
+}
+
+/// Whether `tag`, the inside of `<…>`, is one of the HTML-like tags that subtitles use.
+fn is_markup_tag(tag: &str) -> bool {
+    let tag = tag.strip_prefix('/').unwrap_or(tag).to_ascii_lowercase();
+    matches!(tag.as_str(), "i" | "b" | "u" | "font") || tag.starts_with("font ")
+}
+
+/// Drops `<i>`, `<b>`, `<u>` and `<font …>` tags and ASS `{\…}` override blocks, and keeps
+/// anything else in brackets, such as `<3` or `{laughs}`, as text.
+fn strip_markup(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(['<', '{']) {
+        plain.push_str(&rest[..start]);
+        let tag = &rest[start..];
+        let end = if tag.starts_with('<') {
+            tag.find('>').filter(|&end| is_markup_tag(&tag[1..end]))
+        } else if tag.starts_with("{\\") {
+            tag.find('}')
+        } else {
+            None
+        };
+        match end {
+            Some(end) => rest = &tag[end + 1..],
+            None => {
+                plain.push_str(&tag[..1]);
+                rest = &tag[1..];
+            }
+        }
+    }
+    plain.push_str(rest);
+    plain
+}
+
+/// The text of a subtitle packet without markup. ASS packets carry the dialogue fields before
+/// the text, and `\N` line breaks.
+pub(crate) fn plain_text(data: &[u8], ass: bool) -> String {
+    let text = String::from_utf8_lossy(data);
+    let plain = if ass {
+        strip_markup(text.splitn(9, ',').nth(8).unwrap_or_default())
+            .replace("\\N", "\n")
+            .replace("\\n", "\n")
+    } else {
+        strip_markup(&text)
+    };
+    plain.trim().to_owned()
+}
+
+/// A 3GPP timed text (mov_text) sample of plain text.
+pub(crate) fn mov_text_sample(text: &str) -> Vec<u8> {
+    let text = &text.as_bytes()[..text.len().min(u16::MAX as usize)];
+    let mut sample = Vec::with_capacity(2 + text.len());
+    sample.extend_from_slice(&(text.len() as u16).to_be_bytes());
+    sample.extend_from_slice(text);
+    sample
+}
+
+/// The text of a 3GPP timed text (mov_text) sample, which comes after its length and before
+/// any style boxes.
+pub(crate) fn mov_text_sample_text(sample: &[u8]) -> String {
+    let text = match sample {
+        [high, low, rest @ ..] => {
+            &rest[..(u16::from_be_bytes([*high, *low]) as usize).min(rest.len())]
+        }
+        _ => &[],
+    };
+    String::from_utf8_lossy(text).trim().to_owned()
+}
+
+/// The sample description of a 3GPP timed text (mov_text) track: white text in the default
+/// font, centred at the bottom of the picture.
+pub(crate) fn mov_text_extradata() -> Vec<u8> {
+    const FONT: &[u8] = b"Serif";
+    let mut extradata = Vec::new();
+    // Display flags, then horizontal and vertical justification.
+    extradata.extend_from_slice(&0u32.to_be_bytes());
+    extradata.extend_from_slice(&[1, 0xff]);
+    // Background colour, and the text box as top, left, bottom and right.
+    extradata.extend_from_slice(&[0, 0, 0, 0]);
+    extradata.extend_from_slice(&[0; 8]);
+    // Default style: start and end character, font ID, face flags, size and colour.
+    extradata.extend_from_slice(&[0, 0, 0, 0, 0, 1, 0, 18, 0xff, 0xff, 0xff, 0xff]);
+    // Font table with the one font.
+    let ftab_len = 4 + 4 + 2 + 2 + 1 + FONT.len();
+    extradata.extend_from_slice(&(ftab_len as u32).to_be_bytes());
+    extradata.extend_from_slice(b"ftab");
+    extradata.extend_from_slice(&1u16.to_be_bytes());
+    extradata.extend_from_slice(&1u16.to_be_bytes());
+    extradata.push(FONT.len() as u8);
+    extradata.extend_from_slice(FONT);
+    extradata
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn srt_timestamps() {
+        assert_eq!(srt_timestamp(0), "00:00:00,000");
+        assert_eq!(srt_timestamp(61_001), "00:01:01,001");
+        assert_eq!(srt_timestamp(3_723_456), "01:02:03,456");
+        assert_eq!(srt_timestamp(100 * 3_600_000), "100:00:00,000");
+    }
+
+    #[test]
+    fn cues_are_split_by_sentence_length() {
+        let cues = split_cues("Hi. How are you? Fine!", 1000, 3000);
+        let spans: Vec<_> = cues
+            .iter()
+            .map(|cue| (cue.start_msec, cue.end_msec, cue.text.as_str()))
+            .collect();
+        assert_eq!(
+            spans,
+            [
+                (1000, 1300, "Hi."),
+                (1300, 2500, "How are you?"),
+                (2500, 3000, "Fine!"),
+            ]
+        );
+        let cues = split_cues("こんにちは。元気？", 0, 1000);
+        assert_eq!(cues.len(), 2);
+        assert_eq!((cues[1].start_msec, cues[1].end_msec), (666, 1000));
+        assert_eq!(split_cues("No full stop", 0, 1000)[0].text, "No full stop");
+        assert!(split_cues("  ", 0, 1000).is_empty());
+        // A window that ends before it starts gives cues of no length rather than negative ones.
+        let cues = split_cues("One. Two.", 1000, 500);
+        assert!(cues
+            .iter()
+            .all(|cue| cue.start_msec == 1000 && cue.end_msec == 1000));
+    }
+
+    #[test]
+    fn srt_files_number_their_cues() {
+        let path = std::env::temp_dir().join(format!("annotai-srt-{}.srt", std::process::id()));
+        write_srt(&path, &split_cues("Hi. Yo.", 0, 2000)).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "1\n00:00:00,000 --> 00:00:01,000\nHi.\n\n2\n00:00:01,000 --> 00:00:02,000\nYo.\n"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn ass_text_comes_after_the_dialogue_fields() {
+        assert_eq!(
+            plain_text(b"0,0,Default,,0,0,0,,Hello, world\\Nagain", true),
+            "Hello, world\nagain"
+        );
+        assert_eq!(plain_text(b"0,0,Default", true), "");
+    }
+
+    #[test]
+    fn markup_is_stripped() {
+        assert_eq!(
+            plain_text(b"<i>Hello</i> <font color=\"#ffffff\">world</font>", false),
+            "Hello world"
+        );
+        assert_eq!(
+            plain_text(b"0,0,Default,,0,0,0,,{\\an8}{\\i1}Hello{\\i0}", true),
+            "Hello"
+        );
+    }
+
+    #[test]
+    fn other_brackets_are_kept() {
+        assert_eq!(plain_text(b"I <3 you {laughs}", false), "I <3 you {laughs}");
+        assert_eq!(plain_text(b"a < b and <i>c", false), "a < b and c");
+        assert_eq!(plain_text(b"{\\i1 unclosed", false), "{\\i1 unclosed");
+    }
+
+    #[test]
+    fn mov_text_samples_round_trip() {
+        let sample = mov_text_sample("Hello, world");
+        assert_eq!(&sample[..2], &[0, 12]);
+        assert_eq!(mov_text_sample_text(&sample), "Hello, world");
+        // Style boxes after the text are not part of it.
+        let mut styled = mov_text_sample("こんにちは");
+        styled.extend_from_slice(b"\0\0\0\x0cstyl\0\0\0\0");
+        assert_eq!(mov_text_sample_text(&styled), "こんにちは");
+        assert_eq!(mov_text_sample_text(&[]), "");
+        assert_eq!(mov_text_sample_text(&[0, 10, b'a']), "a");
+    }
+}
@@ -0,0 +1,207 @@
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Transfer {
+    /// SMPTE ST 2084, as in HDR10
+    Pq,
+    /// ARIB STD-B67 hybrid log-gamma
+    Hlg,
+}
+
+/// Peak of the HDR source relative to SDR reference white (1000 over 100 nits).
+const PEAK: f32 = 10.0;
+const ENCODED_STEPS: usize = 4096;
+
+/// BT.2020 to BT.709 primaries, in linear light.
+const GAMUT: [[f32; 3]; 3] = [
+    [1.6605, -0.5876, -0.0728],
+    [-0.1246, 1.1329, -0.0083],
+    [-0.0182, -0.1006, 1.1187],
+];
+
+fn pq_to_linear(value: f32) -> f32 {
+    const M1: f32 = 0.159_301_76;
+    const M2: f32 = 78.84375;
+    const C1: f32 = 0.835_937_5;
+    const C2: f32 = 18.851_563;
+    const C3: f32 = 18.6875;
+    let power = value.powf(1.0 / M2);
+    let nits = ((power - C1).max(0.0) / (C2 - C3 * power)).powf(1.0 / M1) * 10000.0;
+    nits / 100.0
+}
+
+fn hlg_to_linear(value: f32) -> f32 {
+    const A: f32 = 0.178_832_77;
+    const B: f32 = 0.284_668_92;
+    const C: f32 = 0.559_910_7;
+    let scene = if value <= 0.5 {
+        value * value / 3.0
+    } else {
+        (((value - C) / A).exp() + B) / 12.0
+    };
+    // The system gamma of a 1000 nit display, applied per channel.
+    scene.powf(1.2) * PEAK
+}
+
+/// Hable's filmic curve, which keeps the midtones and rolls off the highlights.
+fn hable(x: f32) -> f32 {
+    const A: f32 = 0.15;
+    const B: f32 = 0.50;
+    const C: f32 = 0.10;
+    const D: f32 = 0.20;
+    const E: f32 = 0.02;
+    const F: f32 = 0.30;
+    (x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F) - E / F
+}
+
+fn bt709_encode(linear: f32) -> f32 {
+    if linear < 0.018 {
+        4.5 * linear
+    } else {
+        1.099 * linear.powf(0.45) - 0.099
+    }
+}
+
+/// Maps HDR BT.2020 pictures to SDR BT.709, with lookup tables for the transfer functions.
+pub(crate) struct ToneMapper {
+    /// Tone mapped linear light of every 16-bit code value
+    linear: Vec<f32>,
+    /// 8-bit BT.709 code values of linear light in [0, 1]
+    encoded: Vec<u8>,
+    full_range: bool,
+}
+
+impl ToneMapper {
+    pub(crate) fn new(transfer: Transfer, full_range: bool) -> Self {
+        let white = hable(PEAK);
+        let linear = (0..=u16::MAX)
+            .map(|code| {
+                let value = code as f32 / u16::MAX as f32;
+                let light = match transfer {
+                    Transfer::Pq => pq_to_linear(value),
+                    Transfer::Hlg => hlg_to_linear(value),
+                };
+                hable(light) / white
+            })
+            .collect();
+        let encoded = (0..=ENCODED_STEPS)
+            .map(|step| (bt709_encode(step as f32 / ENCODED_STEPS as f32) * 255.0).round() as u8)
+            .collect();
+        Self {
+            linear,
+            encoded,
+            full_range,
+        }
+    }
+
+    fn code(value: f32) -> usize {
+        (value.clamp(0.0, 1.0) * u16::MAX as f32).round() as usize
+    }
+
+    /// Turns a pixel of 16-bit BT.2020 Y'CbCr into 8-bit BT.709 R'G'B'.
+    pub(crate) fn rgb(&self, y: u16, cb: u16, cr: u16) -> [u8; 3] {
+        const KR: f32 = 0.2627;
+        const KB: f32 = 0.0593;
+        let (luma, blue, red) = if self.full_range {
+            (
+                y as f32 / u16::MAX as f32,
+                cb as f32 / u16::MAX as f32 - 0.5,
+                cr as f32 / u16::MAX as f32 - 0.5,
+            )
+        } else {
+            (
+                (y as f32 / 256.0 - 16.0) / 219.0,
+                (cb as f32 / 256.0 - 128.0) / 224.0,
+                (cr as f32 / 256.0 - 128.0) / 224.0,
+            )
+        };
+        let r = luma + 2.0 * (1.0 - KR) * red;
+        let b = luma + 2.0 * (1.0 - KB) * blue;
+        let g = (luma - KR * r - KB * b) / (1.0 - KR - KB);
+        let light = [
+            self.linear[Self::code(r)],
+            self.linear[Self::code(g)],
+            self.linear[Self::code(b)],
+        ];
+        GAMUT.map(|row| {
+            let value = row[0] * light[0] + row[1] * light[1] + row[2] * light[2];
+            self.encoded[(value.clamp(0.0, 1.0) * ENCODED_STEPS as f32).round() as usize]
+        })
+    }
+
+    /// Turns a pixel of 16-bit BT.2020 Y'CbCr into 8-bit limited range BT.709 Y'CbCr.
+    pub(crate) fn ycbcr(&self, y: u16, cb: u16, cr: u16) -> [u8; 3] {
+        let [r, g, b] = self.rgb(y, cb, cr).map(|value| value as f32 / 255.0);
+        let luma = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+        [
+            (16.0 + 219.0 * luma).round() as u8,
+            (128.0 + 224.0 * (b - luma) / 1.8556).round() as u8,
+            (128.0 + 224.0 * (r - luma) / 1.5748).round() as u8,
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            actual,
+            tolerance,
+            expected
+        );
+    }
+
+    #[test]
+    fn transfer_functions() {
+        assert_eq!(pq_to_linear(0.0), 0.0);
+        assert_close(pq_to_linear(1.0), 100.0, 0.01);
+        // SDR reference white is at about half of the PQ range.
+        assert_close(pq_to_linear(0.508), 1.0, 0.01);
+        assert_eq!(hlg_to_linear(0.0), 0.0);
+        assert_close(hlg_to_linear(1.0), PEAK, 0.05);
+        // The two halves of the HLG curve meet.
+        assert_close(hlg_to_linear(0.5), hlg_to_linear(0.500_01), 0.001);
+    }
+
+    #[test]
+    fn curve_endpoints() {
+        assert_close(hable(0.0), 0.0, 1e-6);
+        for transfer in [Transfer::Pq, Transfer::Hlg] {
+            let mapper = ToneMapper::new(transfer, false);
+            assert_close(mapper.linear[0], 0.0, 1e-6);
+            // Rising, up to rounding around black.
+            assert!(mapper
+                .linear
+                .windows(2)
+                .all(|pair| pair[0] <= pair[1] + 1e-6));
+            assert_eq!(mapper.encoded[0], 0);
+            assert_eq!(mapper.encoded[ENCODED_STEPS], 255);
+        }
+        // The HDR peak is SDR white.
+        let mapper = ToneMapper::new(Transfer::Hlg, false);
+        assert_close(mapper.linear[u16::MAX as usize], 1.0, 0.01);
+    }
+
+    #[test]
+    fn gamut_keeps_white() {
+        for row in GAMUT {
+            assert_close(row.iter().sum(), 1.0, 0.001);
+        }
+    }
+
+    #[test]
+    fn black_and_white_pixels() {
+        for transfer in [Transfer::Pq, Transfer::Hlg] {
+            let mapper = ToneMapper::new(transfer, false);
+            assert_eq!(mapper.rgb(16 << 8, 128 << 8, 128 << 8), [0, 0, 0]);
+            assert_eq!(mapper.rgb(235 << 8, 128 << 8, 128 << 8), [255, 255, 255]);
+            assert_eq!(mapper.ycbcr(16 << 8, 128 << 8, 128 << 8), [16, 128, 128]);
+            assert_eq!(mapper.ycbcr(235 << 8, 128 << 8, 128 << 8), [235, 128, 128]);
+            let mapper = ToneMapper::new(transfer, true);
+            assert_eq!(mapper.rgb(0, 0x8000, 0x8000), [0, 0, 0]);
+            assert_eq!(mapper.rgb(u16::MAX, 0x8000, 0x8000), [255, 255, 255]);
+        }
+    }
+}
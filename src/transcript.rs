@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::ai;
+use crate::cache::Cache;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Transcriber {
+    /// OpenAI Whisper API
+    Openai,
+    /// A local whisper.cpp compatible command
+    Local,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct TranscriptSegment {
+    pub(crate) start_msec: i64,
+    pub(crate) end_msec: i64,
+    pub(crate) text: String,
+}
+
+pub(crate) struct LocalWhisper<'a> {
+    pub(crate) command: &'a str,
+    pub(crate) model: Option<&'a Path>,
+}
+
+#[derive(Deserialize)]
+struct WhisperCppOutput {
+    transcription: Vec<WhisperCppSegment>,
+}
+
+#[derive(Deserialize)]
+struct WhisperCppSegment {
+    offsets: WhisperCppOffsets,
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct WhisperCppOffsets {
+    from: i64,
+    to: i64,
+}
+
+pub(crate) async fn transcribe(
+    audio_path: &Path,
+    transcriber: Transcriber,
+    local: &LocalWhisper<'_>,
+    settings: &ai::Settings,
+    cache: &Cache,
+) -> anyhow::Result<Vec<TranscriptSegment>> {
+    let segments = match transcriber {
+        Transcriber::Openai => ai::transcribe(audio_path, settings, cache).await?,
+        Transcriber::Local => transcribe_local(audio_path, local).await?,
+    };
+    Ok(segments
+        .into_iter()
+        .map(|segment| TranscriptSegment {
+            text: segment.text.trim().to_owned(),
+            ..segment
+        })
+        .filter(|segment| !segment.text.is_empty())
+        .collect())
+}
+
+async fn transcribe_local(
+    audio_path: &Path,
+    local: &LocalWhisper<'_>,
+) -> anyhow::Result<Vec<TranscriptSegment>> {
+    let model = local.model.ok_or(anyhow::anyhow!(
+        "--whisper-model is required for local transcription"
+    ))?;
+    // whisper.cpp appends the extension to the output prefix itself.
+    let output_prefix = audio_path.with_extension("");
+    let output_path = PathBuf::from(format!("{}.json", output_prefix.display()));
+
+    let status = tokio::process::Command::new(local.command)
+        .arg("-m")
+        .arg(model)
+        .arg("-f")
+        .arg(audio_path)
+        .arg("-oj")
+        .arg("-of")
+        .arg(&output_prefix)
+        .arg("-np")
+        .status()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to run {}: {}", local.command, e))?;
+    if !status.success() {
+        anyhow::bail!("{} exited with {}", local.command, status);
+    }
+
+    let output: WhisperCppOutput = serde_json::from_slice(&fs::read(&output_path)?)?;
+    Ok(output
+        .transcription
+        .into_iter()
+        .map(|segment| TranscriptSegment {
+            start_msec: segment.offsets.from,
+            end_msec: segment.offsets.to,
+            text: segment.text,
+        })
+        .collect())
+}
+
+pub(crate) fn prompt_section(segments: &[TranscriptSegment]) -> String {
+    let mut section =
+        String::from("\n\nTranscript of the original audio (seconds from the start of the clip):");
+    for segment in segments {
+        section.push_str(&format!(
+            "\n[{:.1}s - {:.1}s] {}",
+            segment.start_msec as f64 / 1000.0,
+            segment.end_msec as f64 / 1000.0,
+            segment.text
+        ));
+    }
+    section
+}
@@ -1,45 +1,1361 @@
 use anyhow::Ok;
 use base64::Engine;
 use ffmpeg::encoder;
-use ffmpeg::util::frame::{audio::Audio, video::Video};
+use ffmpeg::util::frame::{self, audio::Audio, video::Video};
 use ffmpeg_next::{
-    self as ffmpeg, channel_layout, codec, decoder, filter, format, media, picture, rescale,
+    self as ffmpeg, channel_layout, codec, color, decoder, filter, format, media, picture, rescale,
     software, Dictionary, Error, Frame, Packet, Rational, Rescale,
 };
 use image::codecs::jpeg;
-use image::ImageBuffer;
-use std::collections::HashMap;
+use image::{imageops, ImageBuffer};
+use std::collections::{HashMap, VecDeque};
 use std::fs;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Once;
+use std::time::SystemTime;
+
+use crate::chapter::Chapter;
+use crate::{subtitle, tonemap};
 
 static INIT: Once = Once::new();
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum LengthPolicy {
+    /// Cut the output at the end of the annotated range
+    Range,
+    /// Extend to the end of the commentary, holding the last frame of the range
+    Freeze,
+    /// Extend to the end of the commentary, continuing the source
+    Continue,
+    /// Speed up the commentary so that it fits in the annotated range
+    Fit,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TempoRange {
+    pub(crate) min: f64,
+    pub(crate) max: f64,
+}
+
+impl TempoRange {
+    /// For speech that is already at its final speed.
+    pub(crate) const UNCHANGED: Self = Self { min: 1.0, max: 1.0 };
+
+    pub(crate) fn fit(&self, audio_msec: i64, window_msec: i64) -> f64 {
+        (audio_msec as f64 / window_msec.max(1) as f64)
+            .max(self.min)
+            .min(self.max)
+    }
+}
+
+impl LengthPolicy {
+    fn tempo(&self, range_msec: i64, overlay_msec: i64, tempo_range: TempoRange) -> f64 {
+        match self {
+            LengthPolicy::Fit => {
+                (overlay_msec as f64 / range_msec.max(1) as f64).max(tempo_range.min)
+            }
+            _ => tempo_range.fit(overlay_msec, range_msec),
+        }
+    }
+
+    pub(crate) fn spoken_msec(
+        &self,
+        range_msec: i64,
+        overlay_msec: i64,
+        tempo_range: TempoRange,
+    ) -> i64 {
+        let spoken_msec =
+            (overlay_msec as f64 / self.tempo(range_msec, overlay_msec, tempo_range)).ceil() as i64;
+        match self {
+            LengthPolicy::Range | LengthPolicy::Fit => spoken_msec.min(range_msec),
+            LengthPolicy::Freeze | LengthPolicy::Continue => spoken_msec,
+        }
+    }
+}
+
+pub(crate) struct CommentaryClip {
+    pub(crate) audio_path: PathBuf,
+    pub(crate) start_msec: i64,
+    pub(crate) window_msec: i64,
+    pub(crate) speed_fitted: bool,
+}
+
+pub(crate) struct CommentaryTrack {
+    pub(crate) clips: Vec<CommentaryClip>,
+    pub(crate) language: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Mix {
+    pub(crate) length_policy: LengthPolicy,
+    pub(crate) tempo_range: TempoRange,
+    pub(crate) source_volume: f64,
+    pub(crate) commentary_volume: f64,
+    /// Cut every clip at the end of its window whatever the length policy, so that it never
+    /// talks over what follows
+    pub(crate) trim_to_windows: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum OutputFormat {
+    Mp4,
+    Mkv,
+    Webm,
+    Mov,
+    Ts,
+    /// HLS playlists with fMP4 or MPEG-TS segments
+    Hls,
+    /// DASH manifest with fMP4 segments
+    Dash,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum HlsSegments {
+    Fmp4,
+    Ts,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Passthrough {
+    /// Subtitles, converted to a text format the container takes where needed
+    Subtitles,
+    /// Attached files such as fonts (mkv only)
+    Attachments,
+    /// Data streams such as timecodes and telemetry
+    Data,
+    /// Chapters, moved along with the trimmed range
+    Chapters,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum HdrOutput {
+    /// Tone map HDR sources to SDR BT.709, which plays the same everywhere
+    ToneMap,
+    /// Keep HDR sources in HDR, with their colour tags, in 10 bits where needed
+    Keep,
+}
+
+/// A scaled video encode of a segmented output, written as `<height>:<kbit/s>`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub(crate) struct Rendition {
+    pub(crate) height: u32,
+    pub(crate) bitrate_kbps: usize,
+}
+
+impl FromStr for Rendition {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let parsed = value
+            .split_once(':')
+            .and_then(|(height, bitrate)| Some((height.parse().ok()?, bitrate.parse().ok()?)))
+            .filter(|&(height, bitrate_kbps): &(u32, usize)| height >= 2 && bitrate_kbps > 0);
+        let (height, bitrate_kbps) = parsed.ok_or(anyhow::anyhow!(
+            "Invalid rendition {}, expected <height>:<kbit/s>",
+            value
+        ))?;
+        Ok(Self {
+            height,
+            bitrate_kbps,
+        })
+    }
+}
+
+impl TryFrom<String> for Rendition {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum VideoCodec {
+    H264,
+    Hevc,
+    Vp9,
+    Av1,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum AudioCodec {
+    Aac,
+    Opus,
+    Mp3,
+    Flac,
+}
+
+fn value_name(value: &impl clap::ValueEnum) -> String {
+    value
+        .to_possible_value()
+        .map(|value| value.get_name().to_owned())
+        .unwrap_or_default()
+}
+
+impl OutputFormat {
+    /// Where the output goes in the output directory; segmented formats get a folder of their own.
+    pub(crate) fn output_path(&self, output_dir: &Path) -> PathBuf {
+        match self {
+            OutputFormat::Mp4 => output_dir.join("transcoded.mp4"),
+            OutputFormat::Mkv => output_dir.join("transcoded.mkv"),
+            OutputFormat::Webm => output_dir.join("transcoded.webm"),
+            OutputFormat::Mov => output_dir.join("transcoded.mov"),
+            OutputFormat::Ts => output_dir.join("transcoded.ts"),
+            OutputFormat::Hls => output_dir.join("hls").join("master.m3u8"),
+            OutputFormat::Dash => output_dir.join("dash").join("manifest.mpd"),
+        }
+    }
+
+    fn muxer(&self) -> &'static str {
+        match self {
+            OutputFormat::Mp4 => "mp4",
+            OutputFormat::Mkv => "matroska",
+            OutputFormat::Webm => "webm",
+            OutputFormat::Mov => "mov",
+            OutputFormat::Ts => "mpegts",
+            OutputFormat::Hls => "hls",
+            OutputFormat::Dash => "dash",
+        }
+    }
+
+    fn is_segmented(&self) -> bool {
+        matches!(self, OutputFormat::Hls | OutputFormat::Dash)
+    }
+
+    fn supports_chapters(&self) -> bool {
+        matches!(
+            self,
+            OutputFormat::Mp4 | OutputFormat::Mkv | OutputFormat::Webm | OutputFormat::Mov
+        )
+    }
+
+    fn supports_video(&self, codec: VideoCodec) -> bool {
+        match self {
+            OutputFormat::Mp4 => true,
+            OutputFormat::Mkv => true,
+            OutputFormat::Webm => matches!(codec, VideoCodec::Vp9 | VideoCodec::Av1),
+            OutputFormat::Mov | OutputFormat::Ts | OutputFormat::Hls => {
+                matches!(codec, VideoCodec::H264 | VideoCodec::Hevc)
+            }
+            OutputFormat::Dash => true,
+        }
+    }
+
+    fn supports_audio(&self, codec: AudioCodec) -> bool {
+        match self {
+            OutputFormat::Mp4 | OutputFormat::Mkv => true,
+            OutputFormat::Webm => codec == AudioCodec::Opus,
+            OutputFormat::Mov | OutputFormat::Hls => {
+                matches!(codec, AudioCodec::Aac | AudioCodec::Mp3)
+            }
+            OutputFormat::Dash => matches!(codec, AudioCodec::Aac | AudioCodec::Opus),
+            OutputFormat::Ts => {
+                matches!(codec, AudioCodec::Aac | AudioCodec::Mp3 | AudioCodec::Opus)
+            }
+        }
+    }
+
+    /// The codec subtitles of `codec` are stored as, converting text subtitles to the one
+    /// text format of the container; `None` when they cannot be stored.
+    fn subtitle_codec(&self, codec: codec::Id) -> Option<codec::Id> {
+        let text = matches!(
+            codec,
+            codec::Id::SUBRIP
+                | codec::Id::SRT
+                | codec::Id::ASS
+                | codec::Id::SSA
+                | codec::Id::WEBVTT
+                | codec::Id::TEXT
+                | codec::Id::MOV_TEXT
+        );
+        match self {
+            // Matroska stores these as they are, and any other text as SubRip.
+            OutputFormat::Mkv
+                if matches!(
+                    codec,
+                    codec::Id::SUBRIP
+                        | codec::Id::ASS
+                        | codec::Id::SSA
+                        | codec::Id::WEBVTT
+                        | codec::Id::DVD_SUBTITLE
+                        | codec::Id::DVB_SUBTITLE
+                        | codec::Id::HDMV_PGS_SUBTITLE
+                ) =>
+            {
+                Some(codec)
+            }
+            OutputFormat::Mkv if text => Some(codec::Id::SUBRIP),
+            OutputFormat::Mp4 | OutputFormat::Mov if text => Some(codec::Id::MOV_TEXT),
+            OutputFormat::Mp4 | OutputFormat::Mov if codec == codec::Id::DVD_SUBTITLE => {
+                Some(codec)
+            }
+            OutputFormat::Webm if text => Some(codec::Id::WEBVTT),
+            OutputFormat::Ts
+                if matches!(codec, codec::Id::DVB_SUBTITLE | codec::Id::DVB_TELETEXT) =>
+            {
+                Some(codec)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl VideoCodec {
+    fn encoder(&self) -> Option<codec::Codec> {
+        match self {
+            VideoCodec::H264 => encoder::find(codec::Id::H264),
+            VideoCodec::Hevc => encoder::find(codec::Id::HEVC),
+            VideoCodec::Vp9 => {
+                encoder::find_by_name("libvpx-vp9").or(encoder::find(codec::Id::VP9))
+            }
+            VideoCodec::Av1 => encoder::find_by_name("libsvtav1")
+                .or(encoder::find_by_name("libaom-av1"))
+                .or(encoder::find(codec::Id::AV1)),
+        }
+    }
+
+    fn options(&self, fixed_gop: bool) -> Dictionary<'static> {
+        let mut options = Dictionary::new();
+        match self {
+            VideoCodec::H264 | VideoCodec::Hevc => options.set("preset", "medium"),
+            VideoCodec::Vp9 => {
+                options.set("deadline", "good");
+                options.set("cpu-used", "4");
+                options.set("row-mt", "1");
+            }
+            VideoCodec::Av1 => {}
+        }
+        // Scene cuts would add keyframes that differ between renditions and break segment alignment.
+        if fixed_gop {
+            match self {
+                VideoCodec::H264 => options.set("x264-params", "scenecut=0"),
+                VideoCodec::Hevc => options.set("x265-params", "scenecut=0"),
+                VideoCodec::Vp9 | VideoCodec::Av1 => {}
+            }
+        }
+        options
+    }
+}
+
+impl AudioCodec {
+    fn encoder(&self) -> Option<codec::Codec> {
+        match self {
+            AudioCodec::Aac => encoder::find(codec::Id::AAC),
+            AudioCodec::Opus => encoder::find_by_name("libopus").or(encoder::find(codec::Id::OPUS)),
+            AudioCodec::Mp3 => {
+                encoder::find_by_name("libmp3lame").or(encoder::find(codec::Id::MP3))
+            }
+            AudioCodec::Flac => encoder::find(codec::Id::FLAC),
+        }
+    }
+}
+
+/// Which input streams are captured and mixed into, and what happens to the others.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct StreamSelection {
+    /// Position among the video streams, or the best one
+    pub(crate) video: Option<usize>,
+    /// Position among the audio streams, or the one in `audio_language`, or the best one
+    pub(crate) audio: Option<usize>,
+    /// ISO 639-2 code
+    pub(crate) audio_language: Option<String>,
+    /// Copy the other audio and video streams untouched instead of dropping them
+    pub(crate) keep_others: bool,
+    pub(crate) passthrough: Vec<Passthrough>,
+    /// Positions among the subtitle streams to copy, along with those in `subtitle_languages`;
+    /// all of them when both are empty
+    pub(crate) subtitles: Vec<usize>,
+    /// ISO 639-2 codes
+    pub(crate) subtitle_languages: Vec<String>,
+}
+
+impl StreamSelection {
+    /// Input index of the chosen stream of `medium`; `None` when the input has no such stream.
+    fn stream_index(
+        &self,
+        input: &format::context::Input,
+        medium: media::Type,
+    ) -> anyhow::Result<Option<usize>> {
+        let (position, language, name) = match medium {
+            media::Type::Video => (self.video, None, "video"),
+            _ => (self.audio, self.audio_language.as_deref(), "audio"),
+        };
+        let streams: Vec<_> = input
+            .streams()
+            .filter(|stream| stream.parameters().medium() == medium)
+            .collect();
+        if let Some(position) = position {
+            let stream = streams.get(position).ok_or(anyhow::anyhow!(
+                "No {} stream {}; the input has {}",
+                name,
+                position,
+                streams.len()
+            ))?;
+            return Ok(Some(stream.index()));
+        }
+        if let Some(language) = language {
+            match streams
+                .iter()
+                .find(|stream| stream.metadata().get("language") == Some(language))
+            {
+                Some(stream) => return Ok(Some(stream.index())),
+                None => println!("No {} {} stream, using the default one", language, name),
+            }
+        }
+        Ok(input.streams().best(medium).map(|stream| stream.index()))
+    }
+
+    /// Input indices of the subtitle streams to copy when subtitles pass through.
+    fn subtitle_indices(&self, input: &format::context::Input) -> anyhow::Result<Vec<usize>> {
+        let streams: Vec<_> = input
+            .streams()
+            .filter(|stream| stream.parameters().medium() == media::Type::Subtitle)
+            .collect();
+        if let Some(&position) = self
+            .subtitles
+            .iter()
+            .find(|&&position| position >= streams.len())
+        {
+            anyhow::bail!(
+                "No subtitle stream {}; the input has {}",
+                position,
+                streams.len()
+            );
+        }
+        for language in &self.subtitle_languages {
+            if !streams
+                .iter()
+                .any(|stream| stream.metadata().get("language") == Some(language.as_str()))
+            {
+                println!("No {} subtitle stream", language);
+            }
+        }
+        let select_all = self.subtitles.is_empty() && self.subtitle_languages.is_empty();
+        Ok(streams
+            .iter()
+            .enumerate()
+            .filter(|(position, stream)| {
+                select_all
+                    || self.subtitles.contains(position)
+                    || stream.metadata().get("language").is_some_and(|language| {
+                        self.subtitle_languages
+                            .iter()
+                            .any(|selected| selected == language)
+                    })
+            })
+            .map(|(_, stream)| stream.index())
+            .collect())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Encoding {
+    pub(crate) format: OutputFormat,
+    pub(crate) video_codec: Option<VideoCodec>,
+    pub(crate) audio_codec: Option<AudioCodec>,
+    pub(crate) segment_sec: u32,
+    pub(crate) hls_segments: HlsSegments,
+    pub(crate) renditions: Vec<Rendition>,
+    pub(crate) hdr_output: HdrOutput,
+    pub(crate) streams: StreamSelection,
+    /// Chapters to write instead of the source's, from the start of the output, cut at its end
+    pub(crate) chapters: Option<Vec<Chapter>>,
+}
+
+impl Encoding {
+    fn video_codec(&self) -> VideoCodec {
+        self.video_codec.unwrap_or(match self.format {
+            OutputFormat::Webm => VideoCodec::Vp9,
+            _ => VideoCodec::H264,
+        })
+    }
+
+    fn audio_codec(&self) -> AudioCodec {
+        self.audio_codec.unwrap_or(match self.format {
+            OutputFormat::Webm => AudioCodec::Opus,
+            _ => AudioCodec::Aac,
+        })
+    }
+
+    pub(crate) fn validate(&self) -> anyhow::Result<()> {
+        if !self.format.supports_video(self.video_codec()) {
+            anyhow::bail!(
+                "{} video cannot be stored in {}",
+                value_name(&self.video_codec()),
+                value_name(&self.format)
+            );
+        }
+        if !self.format.supports_audio(self.audio_codec()) {
+            anyhow::bail!(
+                "{} audio cannot be stored in {}",
+                value_name(&self.audio_codec()),
+                value_name(&self.format)
+            );
+        }
+        if !self.renditions.is_empty() && !self.format.is_segmented() {
+            anyhow::bail!("Renditions need the hls or dash format");
+        }
+        if self.segment_sec == 0 {
+            anyhow::bail!("The segment duration must be at least 1 second");
+        }
+        if self.streams.keep_others && self.format.is_segmented() {
+            anyhow::bail!("Other streams cannot be kept in hls or dash output");
+        }
+        Ok(())
+    }
+
+    fn muxer_options(
+        &self,
+        output_path: &Path,
+        audio_streams: &[(usize, Option<&str>)],
+        video_count: usize,
+    ) -> anyhow::Result<Dictionary<'static>> {
+        let mut options = Dictionary::new();
+        let file_name = output_path
+            .file_name()
+            .ok_or(anyhow::anyhow!("Invalid path"))?
+            .to_string_lossy();
+        match self.format {
+            OutputFormat::Mp4 | OutputFormat::Mov => {
+                // Put the index first so that players can start before the download ends.
+                options.set("movflags", "+faststart");
+            }
+            OutputFormat::Hls => {
+                let dir = output_path.parent().unwrap_or(Path::new("."));
+                let (segment_type, extension) = match self.hls_segments {
+                    HlsSegments::Fmp4 => ("fmp4", "m4s"),
+                    HlsSegments::Ts => ("mpegts", "ts"),
+                };
+                options.set("hls_time", &self.segment_sec.to_string());
+                options.set("hls_playlist_type", "vod");
+                options.set("hls_segment_type", segment_type);
+                options.set(
+                    "hls_segment_filename",
+                    &dir.join(format!("stream_%v_%05d.{}", extension))
+                        .to_string_lossy(),
+                );
+                options.set("hls_fmp4_init_filename", "init_%v.mp4");
+                options.set("master_pl_name", &file_name);
+                // The commentary languages become alternate renditions of one audio group.
+                let mut var_streams = Vec::new();
+                for (index, (_, language)) in audio_streams.iter().enumerate() {
+                    let mut var_stream = format!("a:{},agroup:audio", index);
+                    if let Some(language) = language {
+                        var_stream += &format!(",language:{}", language);
+                    }
+                    if index == 0 {
+                        var_stream += ",default:yes";
+                    }
+                    var_streams.push(var_stream);
+                }
+                for index in 0..video_count {
+                    var_streams.push(if audio_streams.is_empty() {
+                        format!("v:{}", index)
+                    } else {
+                        format!("v:{},agroup:audio", index)
+                    });
+                }
+                options.set("var_stream_map", &var_streams.join(" "));
+            }
+            OutputFormat::Dash => {
+                options.set("seg_duration", &self.segment_sec.to_string());
+                options.set("use_template", "1");
+                options.set("use_timeline", "1");
+                // All video renditions switch within one set; each language is a set of its own.
+                let mut adaptation_sets = vec!["id=0,streams=v".to_owned()];
+                for (index, (stream_index, _)) in audio_streams.iter().enumerate() {
+                    adaptation_sets.push(format!("id={},streams={}", index + 1, stream_index));
+                }
+                options.set("adaptation_sets", &adaptation_sets.join(" "));
+            }
+            OutputFormat::Mkv | OutputFormat::Webm | OutputFormat::Ts => {}
+        }
+        Ok(options)
+    }
+}
+
+struct OverlayPlan {
+    tempos: Vec<Vec<f64>>,
+    mix: Mix,
+    trim: bool,
+    amix_duration: &'static str,
+    read_msec: i64,
+    hold_msec: Option<i64>,
+}
+
+impl OverlayPlan {
+    fn new(mix: Mix, range_msec: i64, tracks: &[CommentaryTrack]) -> anyhow::Result<Self> {
+        let mut tempos = Vec::new();
+        let mut commentary_msec = range_msec;
+        for track in tracks {
+            let mut track_tempos = Vec::new();
+            for clip in &track.clips {
+                let overlay_msec = duration_msec(&clip.audio_path)?;
+                let tempo_range = if clip.speed_fitted {
+                    TempoRange::UNCHANGED
+                } else {
+                    mix.tempo_range
+                };
+                let tempo = mix
+                    .length_policy
+                    .tempo(clip.window_msec, overlay_msec, tempo_range);
+                let mut spoken_msec = (overlay_msec as f64 / tempo).ceil() as i64;
+                if mix.trim_to_windows {
+                    spoken_msec = spoken_msec.min(clip.window_msec);
+                }
+                commentary_msec = commentary_msec.max(clip.start_msec + spoken_msec);
+                track_tempos.push(tempo);
+            }
+            tempos.push(track_tempos);
+        }
+        Ok(match mix.length_policy {
+            LengthPolicy::Range | LengthPolicy::Fit => Self {
+                tempos,
+                mix,
+                trim: true,
+                amix_duration: "first",
+                read_msec: range_msec,
+                hold_msec: None,
+            },
+            LengthPolicy::Freeze => Self {
+                tempos,
+                mix,
+                trim: false,
+                amix_duration: "longest",
+                read_msec: range_msec,
+                hold_msec: Some(commentary_msec),
+            },
+            LengthPolicy::Continue => Self {
+                tempos,
+                mix,
+                trim: false,
+                amix_duration: "first",
+                read_msec: commentary_msec,
+                hold_msec: None,
+            },
+        })
+    }
+
+    fn filter_spec(&self, track_index: usize, track: &CommentaryTrack) -> anyhow::Result<String> {
+        let mut chains = Vec::new();
+        let mut labels = String::new();
+        for (clip_index, (clip, tempo)) in track
+            .clips
+            .iter()
+            .zip(&self.tempos[track_index])
+            .enumerate()
+        {
+            let mut chain = format!(
+                "amovie={},{}",
+                filter_value(
+                    clip.audio_path
+                        .to_str()
+                        .ok_or(anyhow::anyhow!("Invalid comment audio path"))?
+                ),
+                atempo_filter(*tempo)
+            );
+            if self.trim || self.mix.trim_to_windows {
+                chain += &format!(",atrim=end={:.3}", clip.window_msec as f64 / 1000.0);
+            }
+            if clip.start_msec > 0 {
+                chain += &format!(",adelay=delays={}:all=1", clip.start_msec);
+            }
+            chains.push(format!(
+                "{},volume={} [ov{}]",
+                chain, self.mix.commentary_volume, clip_index
+            ));
+            labels += &format!("[ov{}]", clip_index);
+        }
+        if track.clips.len() > 1 {
+            // Clips never overlap, so sum them without amix's level normalization.
+            chains.push(format!(
+                "{} amix=inputs={}:duration=longest:normalize=0 [ov]",
+                labels,
+                track.clips.len()
+            ));
+        } else {
+            chains.push(format!("{} anull [ov]", labels));
+        }
+        chains.push(format!(
+            "[in]volume={} [in_vol]; [in_vol][ov] amix=inputs=2:duration={} [out]",
+            self.mix.source_volume, self.amix_duration
+        ));
+        Ok(chains.join("; "))
+    }
+}
+
+/// Escapes a filter option value, such as a file name, for a filter graph description: once
+/// for the option parser and once more for the graph parser.
+fn filter_value(value: &str) -> String {
+    let escape = |value: &str, special: &[char]| {
+        let mut escaped = String::with_capacity(value.len());
+        for c in value.chars() {
+            if special.contains(&c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    };
+    escape(
+        &escape(value, &['\\', '\'', ':']),
+        &['\\', '\'', '[', ']', ',', ';'],
+    )
+}
+
+fn atempo_filter(tempo: f64) -> String {
+    // Older atempo builds only accept factors in [0.5, 2.0], so chain them.
+    let mut filters = Vec::new();
+    let mut remaining = tempo;
+    while remaining > 2.0 {
+        filters.push("atempo=2.0".to_owned());
+        remaining /= 2.0;
+    }
+    while remaining < 0.5 {
+        filters.push("atempo=0.5".to_owned());
+        remaining /= 0.5;
+    }
+    filters.push(format!("atempo={:.4}", remaining));
+    filters.join(",")
+}
+
 pub(crate) fn init() {
     INIT.call_once(|| {
         ffmpeg::init().unwrap();
     });
 }
 
+pub(crate) fn duration_msec(input_path: &Path) -> anyhow::Result<i64> {
+    let input = format::input(&input_path)?;
+    if input.duration() <= 0 {
+        return Err(anyhow::anyhow!(
+            "Unknown duration: {}",
+            input_path.display()
+        ));
+    }
+    Ok(input.duration().rescale(rescale::TIME_BASE, (1, 1000)))
+}
+
+pub(crate) struct CapturedFrame {
+    pub(crate) path: PathBuf,
+    pub(crate) timestamp_msec: i64,
+    pub(crate) base64: String,
+}
+
+pub(crate) fn jpeg_base64(jpeg_data: &[u8]) -> String {
+    use base64::prelude::BASE64_STANDARD;
+
+    "data:image/jpeg;base64,".to_owned() + &BASE64_STANDARD.encode(jpeg_data)
+}
+
+/// Clockwise rotation in degrees, a multiple of 90, that turns a picture upright according
+/// to its display matrix.
+fn display_rotation(matrix: &[u8]) -> u32 {
+    let values: Vec<f64> = matrix
+        .chunks_exact(4)
+        .take(9)
+        .map(|bytes| i32::from_ne_bytes(bytes.try_into().unwrap_or_default()) as f64)
+        .collect();
+    if values.len() < 9 {
+        return 0;
+    }
+    let scale_x = values[0].hypot(values[3]);
+    let scale_y = values[1].hypot(values[4]);
+    if scale_x == 0.0 || scale_y == 0.0 {
+        return 0;
+    }
+    let degrees = (values[1] / scale_y)
+        .atan2(values[0] / scale_x)
+        .to_degrees();
+    ((degrees / 90.0).round() as i64).rem_euclid(4) as u32 * 90
+}
+
+/// Converts the frame to `format` at `width` x `height`, and makes a new scaler when the
+/// input or output changes.
+fn scale(
+    scaler: &mut Option<software::scaling::context::Context>,
+    frame: &Video,
+    format: format::Pixel,
+    width: u32,
+    height: u32,
+) -> anyhow::Result<Video> {
+    // The pictures may not match the decoder parameters, and may change size or format midway.
+    if scaler.as_ref().is_none_or(|scaler| {
+        let (input, output) = (scaler.input(), scaler.output());
+        (input.format, input.width, input.height) != (frame.format(), frame.width(), frame.height())
+            || (output.format, output.width, output.height) != (format, width, height)
+    }) {
+        *scaler = Some(software::scaling::context::Context::get(
+            frame.format(),
+            frame.width(),
+            frame.height(),
+            format,
+            width,
+            height,
+            software::scaling::Flags::BICUBIC,
+        )?);
+    }
+    let scaler = scaler.as_mut().ok_or(anyhow::anyhow!("No scaler"))?;
+    let mut scaled = Video::empty();
+    scaler.run(frame, &mut scaled)?;
+    scaled.set_pts(frame.pts());
+    Ok(scaled)
+}
+
+fn hdr_transfer(transfer: color::TransferCharacteristic) -> Option<tonemap::Transfer> {
+    match transfer {
+        color::TransferCharacteristic::SMPTE2084 => Some(tonemap::Transfer::Pq),
+        color::TransferCharacteristic::ARIB_STD_B67 => Some(tonemap::Transfer::Hlg),
+        _ => None,
+    }
+}
+
+type CachedToneMapper = Option<(tonemap::Transfer, bool, tonemap::ToneMapper)>;
+
+/// The tone mapper for the frame when it is HDR, made anew when the transfer or range changes.
+fn tone_mapper<'a>(
+    cache: &'a mut CachedToneMapper,
+    frame: &Video,
+) -> Option<&'a tonemap::ToneMapper> {
+    let transfer = hdr_transfer(frame.color_transfer_characteristic())?;
+    let full_range = frame.color_range() == color::Range::JPEG;
+    if cache.as_ref().is_none_or(|(cached, cached_full_range, _)| {
+        (*cached, *cached_full_range) != (transfer, full_range)
+    }) {
+        *cache = Some((
+            transfer,
+            full_range,
+            tonemap::ToneMapper::new(transfer, full_range),
+        ));
+    }
+    cache.as_ref().map(|(_, _, tone_mapper)| tone_mapper)
+}
+
+/// Calls `pixel` with the position and Y'CbCr values of every pixel of a YUV444P16LE frame.
+fn for_each_pixel16(frame: &Video, mut pixel: impl FnMut(usize, usize, [u16; 3])) {
+    let planes = [frame.data(0), frame.data(1), frame.data(2)];
+    let strides = [frame.stride(0), frame.stride(1), frame.stride(2)];
+    for y in 0..frame.height() as usize {
+        for x in 0..frame.width() as usize {
+            pixel(
+                x,
+                y,
+                [0, 1, 2].map(|plane| {
+                    let offset = y * strides[plane] + x * 2;
+                    u16::from_le_bytes([planes[plane][offset], planes[plane][offset + 1]])
+                }),
+            );
+        }
+    }
+}
+
+/// Turns decoded pictures into upright RGB images with square pixels, tone mapped when HDR.
+struct FrameConverter {
+    scaler: Option<software::scaling::context::Context>,
+    tone_mapper: CachedToneMapper,
+    stream_rotation: u32,
+}
+
+impl FrameConverter {
+    fn new(stream: &format::stream::Stream) -> Self {
+        let stream_rotation = stream
+            .side_data()
+            .find(|side_data| side_data.kind() == codec::packet::side_data::Type::DisplayMatrix)
+            .map_or(0, |side_data| display_rotation(side_data.data()));
+        Self {
+            scaler: None,
+            tone_mapper: None,
+            stream_rotation,
+        }
+    }
+
+    fn convert(&mut self, decoded: &Video) -> anyhow::Result<image::RgbImage> {
+        let aspect_ratio = Some(decoded.aspect_ratio())
+            .filter(|ratio| ratio.numerator() > 0 && ratio.denominator() > 0)
+            .unwrap_or(Rational(1, 1));
+        let width = (decoded.width() as i64 * aspect_ratio.numerator() as i64
+            / aspect_ratio.denominator() as i64)
+            .max(1) as u32;
+        let height = decoded.height();
+        let image_buffer = match tone_mapper(&mut self.tone_mapper, decoded) {
+            Some(tone_mapper) => {
+                let frame = scale(
+                    &mut self.scaler,
+                    decoded,
+                    format::Pixel::YUV444P16LE,
+                    width,
+                    height,
+                )?;
+                let mut image_buffer = image::RgbImage::new(width, height);
+                for_each_pixel16(&frame, |x, y, [luma, blue, red]| {
+                    image_buffer.put_pixel(
+                        x as u32,
+                        y as u32,
+                        image::Rgb(tone_mapper.rgb(luma, blue, red)),
+                    );
+                });
+                image_buffer
+            }
+            None => {
+                let frame = scale(
+                    &mut self.scaler,
+                    decoded,
+                    format::Pixel::RGB24,
+                    width,
+                    height,
+                )?;
+                // Rows are padded to the stride, so copy only the pixels of each.
+                let stride = frame.stride(0);
+                let row_len = width as usize * 3;
+                let mut pixels = Vec::with_capacity(row_len * height as usize);
+                for row in frame.data(0).chunks(stride).take(height as usize) {
+                    pixels.extend_from_slice(&row[..row_len]);
+                }
+                ImageBuffer::<image::Rgb<u8>, _>::from_raw(width, height, pixels)
+                    .ok_or(anyhow::anyhow!("Failed to create image buffer"))?
+            }
+        };
+
+        let rotation = decoded
+            .side_data(frame::side_data::Type::DisplayMatrix)
+            .map_or(self.stream_rotation, |side_data| {
+                display_rotation(side_data.data())
+            });
+        Ok(match rotation {
+            90 => imageops::rotate90(&image_buffer),
+            180 => imageops::rotate180(&image_buffer),
+            270 => imageops::rotate270(&image_buffer),
+            _ => image_buffer,
+        })
+    }
+}
+
+fn encode_jpeg(image_buffer: &image::RgbImage) -> anyhow::Result<Vec<u8>> {
+    let mut jpeg_data = Vec::new();
+    let mut encoder = jpeg::JpegEncoder::new_with_quality(&mut jpeg_data, 100);
+    encoder.encode(
+        &image_buffer,
+        image_buffer.width(),
+        image_buffer.height(),
+        image::ExtendedColorType::Rgb8,
+    )?;
+    Ok(jpeg_data)
+}
+
+pub(crate) struct LiveFrame {
+    pub(crate) captured_at: SystemTime,
+    pub(crate) base64: String,
+}
+
+pub(crate) struct CommentaryAudio {
+    /// When the part of the source that the commentary should start at was received
+    pub(crate) start_at: SystemTime,
+    /// Mono samples at the restream rate, from `commentary_samples`
+    pub(crate) samples: Vec<f32>,
+}
+
+pub(crate) struct Restream {
+    pub(crate) url: String,
+    /// How long the source is held back, which gives the model time to respond
+    pub(crate) delay_msec: u64,
+    pub(crate) source_volume: f64,
+    pub(crate) commentary_volume: f64,
+    pub(crate) commentary: std::sync::mpsc::Receiver<CommentaryAudio>,
+}
+
+const RESTREAM_RATE: u32 = 48000;
+
+/// Decodes commentary audio for the restream, off the thread that reads the live source.
+pub(crate) fn commentary_samples(path: &Path) -> anyhow::Result<Vec<f32>> {
+    let mut samples = Vec::new();
+    decode_audio_range(
+        path,
+        0,
+        duration_msec(path)? / 1000 + 1,
+        format::Sample::F32(format::sample::Type::Packed),
+        Some(RESTREAM_RATE),
+        &StreamSelection::default(),
+        |_, audio| {
+            samples.extend_from_slice(audio.plane::<f32>(0));
+            Ok(())
+        },
+    )?;
+    Ok(samples)
+}
+
+struct RestreamSource {
+    input_index: usize,
+    input_time_base: Rational,
+    decoder: decoder::Audio,
+    resampler: software::resampling::context::Context,
+}
+
+struct RestreamAudio {
+    /// The audio of the live source; without it the commentary is mixed into silence
+    source: Option<RestreamSource>,
+    output_index: usize,
+    encoder: encoder::Audio,
+    mixed: [Vec<f32>; 2],
+    next_pts: Option<i64>,
+    /// Decoding time of the last video packet, which paces the silence
+    last_video_dts: Option<i64>,
+}
+
+impl RestreamAudio {
+    /// Mixes the commentary into the source samples and encodes every full frame.
+    fn mix(
+        &mut self,
+        (left, right): (&[f32], &[f32]),
+        commentary: &mut VecDeque<f32>,
+        (source_volume, commentary_volume): (f32, f32),
+        output: &mut format::context::Output,
+    ) -> anyhow::Result<()> {
+        for (left, right) in left.iter().zip(right) {
+            let commentary = commentary.pop_front().unwrap_or(0.0) * commentary_volume;
+            self.mixed[0].push(left * source_volume + commentary);
+            self.mixed[1].push(right * source_volume + commentary);
+        }
+
+        let output_time_base = output
+            .stream(self.output_index)
+            .map(|stream| stream.time_base())
+            .ok_or(anyhow::anyhow!(Error::StreamNotFound))?;
+        let frame_size = (self.encoder.frame_size() as usize).max(1);
+        while self.mixed[0].len() >= frame_size {
+            let mut frame = Audio::new(
+                format::Sample::F32(format::sample::Type::Planar),
+                frame_size,
+                channel_layout::ChannelLayout::STEREO,
+            );
+            frame.set_rate(RESTREAM_RATE);
+            for (channel, mixed) in self.mixed.iter_mut().enumerate() {
+                frame
+                    .plane_mut::<f32>(channel)
+                    .copy_from_slice(&mixed[..frame_size]);
+                mixed.drain(..frame_size);
+            }
+            let pts = self.next_pts.unwrap_or(0);
+            frame.set_pts(Some(pts));
+            self.next_pts = Some(pts + frame_size as i64);
+            self.encoder.send_frame(&frame)?;
+
+            let mut encoded = Packet::empty();
+            while self.encoder.receive_packet(&mut encoded).is_ok() {
+                encoded.set_stream(self.output_index);
+                encoded.rescale_ts(Rational(1, RESTREAM_RATE as i32), output_time_base);
+                encoded.write_interleaved(output)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Passes the video of a live source through to a network output after a delay, with the
+/// commentary mixed into its audio.
+struct Restreamer {
+    output: format::context::Output,
+    delay: std::time::Duration,
+    source_volume: f32,
+    commentary_volume: f32,
+    delayed: VecDeque<(SystemTime, Packet)>,
+    video: Option<(usize, usize, Rational)>,
+    audio: RestreamAudio,
+    commentary_receiver: std::sync::mpsc::Receiver<CommentaryAudio>,
+    scheduled: Vec<CommentaryAudio>,
+    commentary: VecDeque<f32>,
+}
+
+impl Restreamer {
+    fn new(input: &format::context::Input, restream: Restream) -> anyhow::Result<Self> {
+        let muxer = match restream.url.split_once("://").map(|(scheme, _)| scheme) {
+            Some("rtmp" | "rtmps") => "flv",
+            Some("srt" | "udp" | "rtp") => "mpegts",
+            _ => anyhow::bail!("Cannot restream to {}", restream.url),
+        };
+        let mut output = format::output_as(&restream.url, muxer)?;
+        let global_header = output
+            .format()
+            .flags()
+            .contains(format::Flags::GLOBAL_HEADER);
+
+        let mut video = None;
+        if let Some(ist) = input.streams().best(media::Type::Video) {
+            let mut ost = output.add_stream(encoder::find(codec::Id::None))?;
+            ost.set_parameters(ist.parameters());
+            video = Some((ist.index(), ost.index(), ist.time_base()));
+        }
+
+        let sample_format = format::Sample::F32(format::sample::Type::Planar);
+        let mut source = None;
+        if let Some(ist) = input.streams().best(media::Type::Audio) {
+            let decoder = codec::context::Context::from_parameters(ist.parameters())?
+                .decoder()
+                .audio()?;
+            let channel_layout = if decoder.channel_layout().is_empty() {
+                channel_layout::ChannelLayout::default(decoder.channels() as i32)
+            } else {
+                decoder.channel_layout()
+            };
+            let resampler = software::resampling::context::Context::get(
+                decoder.format(),
+                channel_layout,
+                decoder.rate(),
+                sample_format,
+                channel_layout::ChannelLayout::STEREO,
+                RESTREAM_RATE,
+            )?;
+            source = Some(RestreamSource {
+                input_index: ist.index(),
+                input_time_base: ist.time_base(),
+                decoder,
+                resampler,
+            });
+        }
+
+        // Always carries audio, so that the commentary is heard even over a silent source.
+        let codec = encoder::find(codec::Id::AAC)
+            .ok_or(anyhow::anyhow!(Error::EncoderNotFound))?
+            .audio()?;
+        let mut ost = output.add_stream(codec)?;
+        let mut encoder = codec::context::Context::from_parameters(ost.parameters())?
+            .encoder()
+            .audio()?;
+        if global_header {
+            encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+        }
+        encoder.set_channel_layout(channel_layout::ChannelLayout::STEREO);
+        encoder.set_rate(RESTREAM_RATE as i32);
+        encoder.set_format(sample_format);
+        encoder.set_bit_rate(128_000);
+        encoder.set_time_base(Rational(1, RESTREAM_RATE as i32));
+        ost.set_time_base(Rational(1, RESTREAM_RATE as i32));
+        let encoder = encoder.open_as(codec)?;
+        ost.set_parameters(&encoder);
+        let audio = RestreamAudio {
+            source,
+            output_index: ost.index(),
+            encoder,
+            mixed: [Vec::new(), Vec::new()],
+            next_pts: None,
+            last_video_dts: None,
+        };
+
+        output.write_header()?;
+        Ok(Self {
+            output,
+            delay: std::time::Duration::from_millis(restream.delay_msec),
+            source_volume: restream.source_volume as f32,
+            commentary_volume: restream.commentary_volume as f32,
+            delayed: VecDeque::new(),
+            video,
+            audio,
+            commentary_receiver: restream.commentary,
+            scheduled: Vec::new(),
+            commentary: VecDeque::new(),
+        })
+    }
+
+    fn push(&mut self, packet: &Packet) -> anyhow::Result<()> {
+        self.delayed.push_back((SystemTime::now(), packet.clone()));
+        while let std::result::Result::Ok(commentary) = self.commentary_receiver.try_recv() {
+            self.scheduled.push(commentary);
+        }
+
+        let now = SystemTime::now();
+        while let Some((received_at, _)) = self.delayed.front() {
+            if now.duration_since(*received_at).unwrap_or_default() < self.delay {
+                break;
+            }
+            let (received_at, packet) = self
+                .delayed
+                .pop_front()
+                .ok_or(anyhow::anyhow!("No packet"))?;
+            self.write(received_at, packet)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, received_at: SystemTime, mut packet: Packet) -> anyhow::Result<()> {
+        // Commentary starts with the first source audio received after the moment it describes.
+        let (due, later): (Vec<_>, Vec<_>) = std::mem::take(&mut self.scheduled)
+            .into_iter()
+            .partition(|commentary| commentary.start_at <= received_at);
+        self.scheduled = later;
+        for commentary in due {
+            // Overlapping commentary plays after the one already playing.
+            self.commentary.extend(commentary.samples);
+        }
+        let volumes = (self.source_volume, self.commentary_volume);
+
+        if let Some((input_index, output_index, input_time_base)) = self.video {
+            if packet.stream() == input_index {
+                let dts = packet
+                    .dts()
+                    .or(packet.pts())
+                    .map(|dts| dts.rescale(input_time_base, Rational(1, RESTREAM_RATE as i32)));
+                packet.set_stream(output_index);
+                packet.set_position(-1);
+                let output_time_base = self
+                    .output
+                    .stream(output_index)
+                    .map(|stream| stream.time_base())
+                    .ok_or(anyhow::anyhow!(Error::StreamNotFound))?;
+                packet.rescale_ts(input_time_base, output_time_base);
+                packet.write_interleaved(&mut self.output)?;
+
+                let audio = &mut self.audio;
+                if let Some(dts) = dts.filter(|_| audio.source.is_none()) {
+                    if audio.next_pts.is_none() {
+                        audio.next_pts = Some(dts);
+                    }
+                    // As much silence as the video advanced, ignoring jumps of a restarted source.
+                    let advanced = audio
+                        .last_video_dts
+                        .map_or(0, |last_dts| dts - last_dts)
+                        .clamp(0, RESTREAM_RATE as i64);
+                    audio.last_video_dts = Some(dts);
+                    let silence = vec![0.0; advanced as usize];
+                    audio.mix(
+                        (silence.as_slice(), silence.as_slice()),
+                        &mut self.commentary,
+                        volumes,
+                        &mut self.output,
+                    )?;
+                }
+                return Ok(());
+            }
+        }
+
+        let audio = &mut self.audio;
+        let Some(source) = &mut audio.source else {
+            return Ok(());
+        };
+        if packet.stream() != source.input_index {
+            return Ok(());
+        }
+        source.decoder.send_packet(&packet)?;
+        let mut decoded = Audio::empty();
+        let mut resampled_frames = Vec::new();
+        while source.decoder.receive_frame(&mut decoded).is_ok() {
+            if audio.next_pts.is_none() {
+                audio.next_pts = decoded.timestamp().map(|pts| {
+                    pts.rescale(source.input_time_base, Rational(1, RESTREAM_RATE as i32))
+                });
+            }
+            resampled_frames.push(resample(&mut source.resampler, &decoded)?);
+        }
+        for resampled in resampled_frames {
+            audio.mix(
+                (resampled.plane::<f32>(0), resampled.plane::<f32>(1)),
+                &mut self.commentary,
+                volumes,
+                &mut self.output,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> anyhow::Result<()> {
+        while let Some((received_at, packet)) = self.delayed.pop_front() {
+            self.write(received_at, packet)?;
+        }
+        self.output.write_trailer()?;
+        Ok(())
+    }
+}
+
+/// Reads a live source (RTMP, RTSP, SRT, UDP, ...) and hands over a picture every
+/// `interval_msec` of stream time until the stream ends or `on_frame` returns false.
+/// With `restream`, the source is also passed on to a network output with the commentary mixed in.
+pub(crate) fn capture_live(
+    url: &str,
+    interval_msec: i64,
+    restream: Option<Restream>,
+    mut on_frame: impl FnMut(LiveFrame) -> bool,
+) -> anyhow::Result<()> {
+    let mut options = Dictionary::new();
+    // Give up on a stalled source instead of waiting forever (microseconds).
+    options.set("rw_timeout", "10000000");
+    options.set("rtsp_transport", "tcp");
+    options.set("fflags", "nobuffer");
+    let mut input = format::input_with_dictionary(url, options)?;
+    let mut restreamer = match restream {
+        Some(restream) => Some(Restreamer::new(&input, restream)?),
+        None => None,
+    };
+
+    let video_stream = input
+        .streams()
+        .best(media::Type::Video)
+        .ok_or(anyhow::anyhow!(Error::StreamNotFound))?;
+    let video_stream_index = video_stream.index();
+    let time_base = video_stream.time_base();
+    let mut decoder = codec::context::Context::from_parameters(video_stream.parameters())?
+        .decoder()
+        .video()?;
+    let mut converter = FrameConverter::new(&video_stream);
+
+    let interval = interval_msec.rescale((1, 1000), time_base);
+    let mut next_pts = None;
+    let mut decoded = Video::empty();
+    'packets: for (stream, packet) in input.packets() {
+        if let Some(restreamer) = &mut restreamer {
+            restreamer.push(&packet)?;
+        }
+        if stream.index() != video_stream_index {
+            continue;
+        }
+        decoder.send_packet(&packet)?;
+        while decoder.receive_frame(&mut decoded).is_ok() {
+            let Some(pts) = decoded.timestamp() else {
+                continue;
+            };
+            // Live timestamps may jump back when the source restarts; start over then.
+            if next_pts.is_some_and(|next_pts| (next_pts - interval..next_pts).contains(&pts)) {
+                continue;
+            }
+            next_pts = Some(pts + interval);
+            let live_frame = LiveFrame {
+                captured_at: SystemTime::now(),
+                base64: jpeg_base64(&encode_jpeg(&converter.convert(&decoded)?)?),
+            };
+            if !on_frame(live_frame) {
+                break 'packets;
+            }
+        }
+    }
+    if let Some(restreamer) = restreamer {
+        restreamer.finish()?;
+    }
+    Ok(())
+}
+
 pub(crate) fn capture_base64(
     input_path: &Path,
     start_sec: i64,
     duration_sec: i64,
     interval_msec: i64,
-) -> anyhow::Result<Vec<String>> {
-    use base64::prelude::BASE64_STANDARD;
-
+    capture_dir: &Path,
+    streams: &StreamSelection,
+) -> anyhow::Result<Vec<CapturedFrame>> {
     let mut input = format::input(&input_path)?;
 
     let start_pos = start_sec.rescale((1, 1), rescale::TIME_BASE);
     input.seek(start_pos, ..start_pos)?;
 
-    let video_stream_context = input
-        .streams()
-        .best(media::Type::Video)
+    let video_stream_index = streams
+        .stream_index(&input, media::Type::Video)?
         .ok_or(anyhow::anyhow!(Error::StreamNotFound))?;
-    let video_stream_index = video_stream_context.index();
 
     let video_stream = input
         .stream(video_stream_index)
@@ -48,16 +1364,7 @@ pub(crate) fn capture_base64(
     let mut decoder = codec::context::Context::from_parameters(codec_params)?
         .decoder()
         .video()?;
-
-    let mut scaler = software::scaling::context::Context::get(
-        decoder.format(),
-        decoder.width(),
-        decoder.height(),
-        format::Pixel::RGB24,
-        decoder.width(),
-        decoder.height(),
-        software::scaling::Flags::BILINEAR,
-    )?;
+    let mut converter = FrameConverter::new(&video_stream);
 
     let time_base = video_stream.time_base();
     let start_pts = start_sec.rescale((1, 1), time_base);
@@ -65,49 +1372,32 @@ pub(crate) fn capture_base64(
     let interval = (interval_msec).rescale((1, 1000), time_base);
     let mut next_pts = start_pts;
 
-    fs::create_dir_all("output/capture")?;
+    fs::create_dir_all(capture_dir)?;
     let mut frame_count = 0;
-    let mut base64_frames = Vec::new();
+    let mut captured_frames = Vec::new();
     let mut receive_and_process_decoded_frames =
         |decoder: &mut decoder::Video| -> Result<(), anyhow::Error> {
             let mut decoded = Video::empty();
             while decoder.receive_frame(&mut decoded).is_ok() {
-                let mut frame = Video::empty();
-                if let Some(pts) = decoded.timestamp() {
-                    if pts < next_pts {
-                        continue;
-                    }
-                    if pts > end_pts {
-                        break;
-                    }
-                    next_pts += interval;
-                } else {
-                    return Err(anyhow::anyhow!("No timestamp"));
+                let pts = decoded.timestamp().ok_or(anyhow::anyhow!("No timestamp"))?;
+                if pts < next_pts {
+                    continue;
                 }
-                scaler.run(&decoded, &mut frame)?;
-                let image_buffer = ImageBuffer::<image::Rgb<u8>, _>::from_raw(
-                    frame.width(),
-                    frame.height(),
-                    frame.data(0).to_vec(),
-                )
-                .ok_or("Failed to create image buffer")
-                .unwrap();
-
-                let mut jpeg_file =
-                    fs::File::create(format!("output/capture/frame_{:04}.jpg", frame_count))?;
-                // println!("Writing frame to file: frame_{:04}.jpg", frame_count);
-                let mut jpeg_data = Vec::new();
-                let mut encoder = jpeg::JpegEncoder::new_with_quality(&mut jpeg_data, 100);
-                encoder.encode(
-                    &image_buffer,
-                    image_buffer.width(),
-                    image_buffer.height(),
-                    image::ExtendedColorType::Rgb8,
-                )?;
+                if pts > end_pts {
+                    break;
+                }
+                next_pts += interval;
+                let jpeg_data = encode_jpeg(&converter.convert(&decoded)?)?;
+
+                let jpeg_path = capture_dir.join(format!("frame_{:04}.jpg", frame_count));
+                let mut jpeg_file = fs::File::create(&jpeg_path)?;
                 jpeg_file.write_all(jpeg_data.as_slice())?;
 
-                let base64_frame = BASE64_STANDARD.encode(jpeg_data);
-                base64_frames.push("data:image/jpeg;base64,".to_owned() + &base64_frame);
+                captured_frames.push(CapturedFrame {
+                    path: jpeg_path,
+                    timestamp_msec: (pts - start_pts).rescale(time_base, (1, 1000)),
+                    base64: jpeg_base64(&jpeg_data),
+                });
                 frame_count += 1;
             }
             Ok(())
@@ -122,7 +1412,235 @@ pub(crate) fn capture_base64(
     decoder.send_eof()?;
     receive_and_process_decoded_frames(&mut decoder)?;
 
-    Ok(base64_frames)
+    Ok(captured_frames)
+}
+
+pub(crate) struct Gap {
+    pub(crate) start_msec: i64,
+    pub(crate) end_msec: i64,
+}
+
+fn resample(
+    resampler: &mut software::resampling::context::Context,
+    input: &Audio,
+) -> anyhow::Result<Audio> {
+    // Room for all of the input, or upsampled samples would pile up inside the resampler.
+    let samples = input.samples() as u64 * resampler.output().rate as u64
+        / resampler.input().rate.max(1) as u64
+        + 64;
+    let mut output = Audio::new(
+        resampler.output().format,
+        samples as usize,
+        resampler.output().channel_layout,
+    );
+    resampler.run(input, &mut output)?;
+    Ok(output)
+}
+
+fn decode_audio_range(
+    input_path: &Path,
+    start_sec: i64,
+    duration_sec: i64,
+    sample_format: format::Sample,
+    sample_rate: Option<u32>,
+    streams: &StreamSelection,
+    mut process_samples: impl FnMut(i64, &Audio) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut input = format::input(&input_path)?;
+
+    let start_pos = start_sec.rescale((1, 1), rescale::TIME_BASE);
+    input.seek(start_pos, ..start_pos)?;
+
+    let audio_stream_index = streams
+        .stream_index(&input, media::Type::Audio)?
+        .ok_or(anyhow::anyhow!(Error::StreamNotFound))?;
+    let audio_stream = input
+        .stream(audio_stream_index)
+        .ok_or(anyhow::anyhow!(Error::StreamNotFound))?;
+    let time_base = audio_stream.time_base();
+    let mut decoder = codec::context::Context::from_parameters(audio_stream.parameters())?
+        .decoder()
+        .audio()?;
+
+    let channel_layout = if decoder.channel_layout().is_empty() {
+        channel_layout::ChannelLayout::default(decoder.channels() as i32)
+    } else {
+        decoder.channel_layout()
+    };
+    let mut resampler = software::resampling::context::Context::get(
+        decoder.format(),
+        channel_layout,
+        decoder.rate(),
+        sample_format,
+        channel_layout::ChannelLayout::MONO,
+        sample_rate.unwrap_or(decoder.rate()),
+    )?;
+
+    let start_pts = start_sec.rescale((1, 1), time_base);
+    let end_pts = (start_sec + duration_sec).rescale((1, 1), time_base);
+    let mut end_msec = None;
+    let mut receive_and_process_decoded_frames =
+        |decoder: &mut decoder::Audio| -> Result<(), anyhow::Error> {
+            let mut decoded = Audio::empty();
+            while decoder.receive_frame(&mut decoded).is_ok() {
+                let pts = decoded.timestamp().ok_or(anyhow::anyhow!("No timestamp"))?;
+                if pts < start_pts || pts >= end_pts {
+                    continue;
+                }
+                let resampled = resample(&mut resampler, &decoded)?;
+                let offset_msec = (pts - start_pts).rescale(time_base, (1, 1000));
+                end_msec = Some(
+                    offset_msec
+                        + resampled.samples() as i64 * 1000 / resampler.output().rate.max(1) as i64,
+                );
+                process_samples(offset_msec, &resampled)?;
+            }
+            Ok(())
+        };
+
+    for (stream, packet) in input.packets() {
+        if stream.index() == audio_stream_index {
+            if packet.pts().is_some_and(|pts| pts >= end_pts) {
+                break;
+            }
+            decoder.send_packet(&packet)?;
+            receive_and_process_decoded_frames(&mut decoder)?;
+        }
+    }
+    decoder.send_eof()?;
+    receive_and_process_decoded_frames(&mut decoder)?;
+
+    // The resampler holds back the last few samples until it is flushed.
+    if let (Some(end_msec), Some(delay)) = (end_msec, resampler.delay()) {
+        let mut flushed = Audio::new(
+            resampler.output().format,
+            delay.output.max(0) as usize + 64,
+            resampler.output().channel_layout,
+        );
+        resampler.flush(&mut flushed)?;
+        if flushed.samples() > 0 {
+            process_samples(end_msec, &flushed)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn detect_quiet_gaps(
+    input_path: &Path,
+    start_sec: i64,
+    duration_sec: i64,
+    silence_db: f64,
+    min_gap_msec: i64,
+    streams: &StreamSelection,
+) -> anyhow::Result<Vec<Gap>> {
+    const WINDOW_MSEC: i64 = 50;
+
+    let threshold = 10_f64.powf(silence_db / 20.0);
+
+    // Loudness of each WINDOW_MSEC window from the start of the range.
+    let mut windows: Vec<bool> = Vec::new();
+    let mut sum_squares = 0_f64;
+    let mut samples = 0_usize;
+    let mut started = false;
+    decode_audio_range(
+        input_path,
+        start_sec,
+        duration_sec,
+        format::Sample::F32(format::sample::Type::Packed),
+        None,
+        streams,
+        |offset_msec, frame| {
+            if !started {
+                // Treat anything before the first decoded frame as quiet.
+                windows.resize((offset_msec / WINDOW_MSEC) as usize, true);
+                started = true;
+            }
+            let window_samples = (frame.rate() as i64 * WINDOW_MSEC / 1000).max(1) as usize;
+            for sample in frame.plane::<f32>(0) {
+                sum_squares += f64::from(*sample) * f64::from(*sample);
+                samples += 1;
+                if samples == window_samples {
+                    windows.push((sum_squares / samples as f64).sqrt() < threshold);
+                    sum_squares = 0.0;
+                    samples = 0;
+                }
+            }
+            Ok(())
+        },
+    )?;
+
+    let range_windows = (duration_sec * 1000 / WINDOW_MSEC) as usize;
+    windows.resize(range_windows, true);
+
+    let mut gaps = Vec::new();
+    let mut gap_start = None;
+    for (index, quiet) in windows.iter().copied().chain([false]).enumerate() {
+        match (quiet, gap_start) {
+            (true, None) => gap_start = Some(index),
+            (false, Some(start)) => {
+                let gap = Gap {
+                    start_msec: start as i64 * WINDOW_MSEC,
+                    end_msec: index as i64 * WINDOW_MSEC,
+                };
+                if gap.end_msec - gap.start_msec >= min_gap_msec {
+                    gaps.push(gap);
+                }
+                gap_start = None;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(gaps)
+}
+
+pub(crate) fn extract_speech_wav(
+    input_path: &Path,
+    start_sec: i64,
+    duration_sec: i64,
+    output_path: &Path,
+    streams: &StreamSelection,
+) -> anyhow::Result<()> {
+    const SAMPLE_RATE: u32 = 16000;
+
+    // 16 kHz mono PCM is what speech recognizers expect.
+    let mut pcm = Vec::new();
+    decode_audio_range(
+        input_path,
+        start_sec,
+        duration_sec,
+        format::Sample::I16(format::sample::Type::Packed),
+        Some(SAMPLE_RATE),
+        streams,
+        |offset_msec, frame| {
+            if pcm.is_empty() {
+                // Silence before the first decoded frame, so that times match the range.
+                pcm.resize((offset_msec * SAMPLE_RATE as i64 / 1000) as usize * 2, 0);
+            }
+            for sample in frame.plane::<i16>(0) {
+                pcm.extend_from_slice(&sample.to_le_bytes());
+            }
+            Ok(())
+        },
+    )?;
+
+    let mut wav = Vec::with_capacity(44 + pcm.len());
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + pcm.len() as u32).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16_u32.to_le_bytes());
+    wav.extend_from_slice(&1_u16.to_le_bytes());
+    wav.extend_from_slice(&1_u16.to_le_bytes());
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+    wav.extend_from_slice(&2_u16.to_le_bytes());
+    wav.extend_from_slice(&16_u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&(pcm.len() as u32).to_le_bytes());
+    wav.extend_from_slice(&pcm);
+    fs::write(output_path, wav)?;
+    Ok(())
 }
 
 enum FrameWrapper<'a> {
@@ -147,6 +1665,8 @@ impl FrameWrapper<'_> {
 }
 
 trait Transcoder {
+    fn output_stream_index(&self) -> usize;
+
     fn flush_filter_graph(&mut self) -> anyhow::Result<()> {
         Ok(())
     }
@@ -159,6 +1679,14 @@ trait Transcoder {
         Ok(())
     }
 
+    fn hold_last_frame(
+        &mut self,
+        _output: &mut format::context::Output,
+        _output_stream_time_base: Rational,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
     fn send_packet_to_decoder(&mut self, packet: &Packet) -> anyhow::Result<()>;
 
     fn send_eof_to_decoder(&mut self) -> anyhow::Result<()>;
@@ -186,6 +1714,19 @@ struct VideoTranscoder {
     encoder: encoder::Video,
     input_time_base: Rational,
     start_sec: i64,
+    frame_duration: i64,
+    hold_until_pts: Option<i64>,
+    last_frame: Option<Video>,
+    /// Format and size of the frames the encoder takes
+    output_format: format::Pixel,
+    width: u32,
+    height: u32,
+    scaler: Option<software::scaling::context::Context>,
+    /// Set when HDR frames are tone mapped to SDR
+    tone_mapping: Option<(
+        Option<software::scaling::context::Context>,
+        CachedToneMapper,
+    )>,
 }
 
 impl VideoTranscoder {
@@ -194,7 +1735,11 @@ impl VideoTranscoder {
         output: &mut format::context::Output,
         output_stream_index: usize,
         start_sec: i64,
+        hold_msec: Option<i64>,
+        encoding: &Encoding,
+        rendition: Option<Rendition>,
     ) -> anyhow::Result<Self> {
+        let video_codec = encoding.video_codec();
         let global_header = output
             .format()
             .flags()
@@ -204,42 +1749,448 @@ impl VideoTranscoder {
             .decoder()
             .video()?;
 
-        let codec = encoder::find(codec::Id::H264);
+        let codec = video_codec.encoder().ok_or(anyhow::anyhow!(
+            "No {} encoder in this FFmpeg build",
+            value_name(&video_codec)
+        ))?;
         let mut output_stream = output.add_stream(codec)?;
-        let mut encoder = codec::context::Context::new_with_codec(
-            codec.ok_or(anyhow::anyhow!(Error::InvalidData))?,
-        )
-        .encoder()
-        .video()?;
-        encoder.set_height(decoder.height());
-        encoder.set_width(decoder.width());
+        let mut encoder = codec::context::Context::new_with_codec(codec)
+            .encoder()
+            .video()?;
+        let frame_rate = Some(input_stream.avg_frame_rate())
+            .filter(|rate| rate.numerator() > 0)
+            .or(decoder.frame_rate())
+            .unwrap_or(Rational(25, 1));
+
+        let (width, height) = match rendition {
+            Some(rendition) => {
+                let width = decoder.width() as u64 * rendition.height as u64
+                    / decoder.height().max(1) as u64;
+                ((width as u32 / 2 * 2).max(2), rendition.height / 2 * 2)
+            }
+            None => (decoder.width(), decoder.height()),
+        };
+        let hdr = hdr_transfer(decoder.color_transfer_characteristic()).is_some();
+        let tone_map = hdr && encoding.hdr_output == HdrOutput::ToneMap;
+        let formats: Vec<format::Pixel> = codec
+            .video()?
+            .formats()
+            .map(|formats| formats.collect())
+            .unwrap_or_default();
+        let supports = |format| formats.is_empty() || formats.contains(&format);
+        let output_format = if !tone_map && supports(decoder.format()) {
+            decoder.format()
+        } else if !tone_map && hdr {
+            if !supports(format::Pixel::YUV420P10LE) {
+                anyhow::bail!(
+                    "The {} encoder cannot keep HDR in 10 bits; use --hdr-output tone-map",
+                    value_name(&video_codec)
+                );
+            }
+            format::Pixel::YUV420P10LE
+        } else if supports(format::Pixel::YUV420P) {
+            format::Pixel::YUV420P
+        } else {
+            formats[0]
+        };
+
+        encoder.set_height(height);
+        encoder.set_width(width);
         encoder.set_aspect_ratio(decoder.aspect_ratio());
-        encoder.set_format(decoder.format());
+        encoder.set_format(output_format);
+        encoder.set_color_range(if output_format == decoder.format() {
+            decoder.color_range()
+        } else {
+            color::Range::MPEG
+        });
         encoder.set_frame_rate(decoder.frame_rate());
         encoder.set_time_base(input_stream.time_base());
+        if let Some(rendition) = rendition {
+            encoder.set_bit_rate(rendition.bitrate_kbps * 1000);
+        }
+        let segmented = encoding.format.is_segmented();
+        if segmented {
+            // One keyframe per segment, at the same place in every rendition.
+            let gop = (f64::from(frame_rate) * encoding.segment_sec as f64).round() as u32;
+            encoder.set_gop(gop.max(1));
+        }
         output_stream.set_parameters(&encoder);
 
         if global_header {
             encoder.set_flags(codec::Flags::GLOBAL_HEADER);
         }
 
-        let mut opts = Dictionary::new();
-        opts.set("preset", "medium");
+        let mut options = video_codec.options(segmented);
+        let colors = if tone_map {
+            (
+                color::Primaries::BT709,
+                color::TransferCharacteristic::BT709,
+                color::Space::BT709,
+            )
+        } else {
+            (
+                decoder.color_primaries(),
+                decoder.color_transfer_characteristic(),
+                decoder.color_space(),
+            )
+        };
+        // The encoder context has no setters for these, but takes them as options.
+        for (key, name) in [
+            ("color_primaries", colors.0.name()),
+            ("color_trc", colors.1.name()),
+            ("colorspace", colors.2.name()),
+        ] {
+            if let Some(name) = name.filter(|name| *name != "unknown") {
+                options.set(key, name);
+            }
+        }
+        let opened_encoder = encoder.open_with(options)?;
+        output_stream.set_parameters(&opened_encoder);
+
+        let frame_duration = 1_i64
+            .rescale(frame_rate.invert(), input_stream.time_base())
+            .max(1);
+
+        Ok(Self {
+            output_stream_index,
+            decoder,
+            encoder: opened_encoder,
+            input_time_base: input_stream.time_base(),
+            start_sec,
+            frame_duration,
+            hold_until_pts: hold_msec.map(|msec| msec.rescale((1, 1000), input_stream.time_base())),
+            last_frame: None,
+            output_format,
+            width,
+            height,
+            scaler: None,
+            tone_mapping: tone_map.then_some((None, None)),
+        })
+    }
+}
+
+impl Transcoder for VideoTranscoder {
+    fn output_stream_index(&self) -> usize {
+        self.output_stream_index
+    }
+
+    fn hold_last_frame(
+        &mut self,
+        output: &mut format::context::Output,
+        output_stream_time_base: Rational,
+    ) -> anyhow::Result<()> {
+        let (Some(hold_until_pts), Some(mut frame)) = (self.hold_until_pts, self.last_frame.take())
+        else {
+            return Ok(());
+        };
+        let mut pts = frame.pts().ok_or(anyhow::anyhow!("No pts"))? + self.frame_duration;
+        while pts < hold_until_pts {
+            frame.set_pts(Some(pts));
+            self.send_frame_to_encoder(FrameWrapper::Video(&frame))?;
+            self.receive_and_process_encoded_packets(output, output_stream_time_base)?;
+            pts += self.frame_duration;
+        }
+        Ok(())
+    }
+
+    fn send_packet_to_decoder(&mut self, packet: &Packet) -> anyhow::Result<()> {
+        self.decoder
+            .send_packet(packet)
+            .map_err(anyhow::Error::from)
+    }
+
+    fn send_eof_to_decoder(&mut self) -> anyhow::Result<()> {
+        self.decoder.send_eof().map_err(anyhow::Error::from)
+    }
+
+    fn receive_and_process_decoded_frames(
+        &mut self,
+        output: &mut format::context::Output,
+        output_stream_time_base: Rational,
+    ) -> anyhow::Result<()> {
+        let mut frame = Video::empty();
+        let start_pts = self.start_sec.rescale((1, 1), self.input_time_base);
+        while self.decoder.receive_frame(&mut frame).is_ok() {
+            let timestamp = frame.timestamp().ok_or(anyhow::anyhow!("No timestamp"))?;
+            frame.set_pts(Some(timestamp - start_pts));
+            frame.set_kind(picture::Type::None);
+            if let Some((hdr_scaler, cached_tone_mapper)) = &mut self.tone_mapping {
+                if let Some(tone_mapper) = tone_mapper(cached_tone_mapper, &frame) {
+                    let hdr_frame = scale(
+                        hdr_scaler,
+                        &frame,
+                        format::Pixel::YUV444P16LE,
+                        frame.width(),
+                        frame.height(),
+                    )?;
+                    let mut mapped =
+                        Video::new(format::Pixel::YUV444P, frame.width(), frame.height());
+                    let strides = [mapped.stride(0), mapped.stride(1), mapped.stride(2)];
+                    for_each_pixel16(&hdr_frame, |x, y, [luma, blue, red]| {
+                        for (plane, value) in
+                            tone_mapper.ycbcr(luma, blue, red).into_iter().enumerate()
+                        {
+                            mapped.data_mut(plane)[y * strides[plane] + x] = value;
+                        }
+                    });
+                    mapped.set_pts(frame.pts());
+                    mapped.set_color_range(color::Range::MPEG);
+                    mapped.set_color_primaries(color::Primaries::BT709);
+                    mapped.set_color_transfer_characteristic(color::TransferCharacteristic::BT709);
+                    mapped.set_color_space(color::Space::BT709);
+                    frame = mapped;
+                }
+            }
+            if (frame.format(), frame.width(), frame.height())
+                != (self.output_format, self.width, self.height)
+            {
+                frame = scale(
+                    &mut self.scaler,
+                    &frame,
+                    self.output_format,
+                    self.width,
+                    self.height,
+                )?;
+            }
+            if self.hold_until_pts.is_some() {
+                self.last_frame = Some(frame.clone());
+            }
+            self.send_frame_to_encoder(FrameWrapper::Video(&frame))?;
+            self.receive_and_process_encoded_packets(output, output_stream_time_base)?;
+        }
+        Ok(())
+    }
+
+    fn send_frame_to_encoder(&mut self, frame_wrapper: FrameWrapper) -> anyhow::Result<()> {
+        self.encoder
+            .send_frame(frame_wrapper.as_video()?)
+            .map_err(anyhow::Error::from)
+    }
+
+    fn send_eof_to_encoder(&mut self) -> anyhow::Result<()> {
+        self.encoder.send_eof().map_err(anyhow::Error::from)
+    }
+
+    fn receive_and_process_encoded_packets(
+        &mut self,
+        output: &mut format::context::Output,
+        output_stream_time_base: Rational,
+    ) -> anyhow::Result<()> {
+        let mut packet = Packet::empty();
+        while self.encoder.receive_packet(&mut packet).is_ok() {
+            packet.set_stream(self.output_stream_index);
+            packet.rescale_ts(self.input_time_base, output_stream_time_base);
+            packet.write_interleaved(output)?;
+        }
+        Ok(())
+    }
+}
+
+struct AudioTranscoder {
+    output_stream_index: usize,
+    decoder: decoder::Audio,
+    encoder: encoder::Audio,
+    filter_graph: filter::Graph,
+    input_time_base: Rational,
+    start_sec: i64,
+}
+
+impl AudioTranscoder {
+    fn new(
+        input_stream: &format::stream::Stream,
+        output: &mut format::context::Output,
+        output_stream_index: usize,
+        filter_spec: &str,
+        language: Option<&str>,
+        start_sec: i64,
+        audio_codec: AudioCodec,
+    ) -> anyhow::Result<Self> {
+        let global_header = output
+            .format()
+            .flags()
+            .contains(format::Flags::GLOBAL_HEADER);
+        let codec_params = input_stream.parameters();
+        let mut decoder = codec::context::Context::from_parameters(codec_params)?
+            .decoder()
+            .audio()?;
+
+        if global_header {
+            decoder.set_flags(codec::Flags::GLOBAL_HEADER);
+        }
+
+        let codec = audio_codec
+            .encoder()
+            .ok_or(anyhow::anyhow!(
+                "No {} encoder in this FFmpeg build",
+                value_name(&audio_codec)
+            ))?
+            .audio()?;
+        let mut output_stream = output.add_stream(codec)?;
+        if let Some(language) = language {
+            let mut metadata = Dictionary::new();
+            metadata.set("language", language);
+            output_stream.set_metadata(metadata);
+        }
+        let context = codec::context::Context::from_parameters(output_stream.parameters())?;
+        let mut encoder = context.encoder().audio()?;
+
+        if global_header {
+            encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+        }
+
+        let channel_layout = codec
+            .channel_layouts()
+            .map(|layouts| layouts.best(decoder.channel_layout().channels()))
+            .unwrap_or(channel_layout::ChannelLayout::STEREO);
+
+        encoder.set_channel_layout(channel_layout);
+        // Opus only encodes a few rates; the filter graph resamples to the one picked here.
+        let rate = match codec.rates() {
+            Some(rates) => {
+                let rates: Vec<i32> = rates.collect();
+                if rates.contains(&(decoder.rate() as i32)) {
+                    decoder.rate() as i32
+                } else {
+                    rates.into_iter().max().unwrap_or(decoder.rate() as i32)
+                }
+            }
+            None => decoder.rate() as i32,
+        };
+        encoder.set_rate(rate);
+        encoder.set_format(
+            codec
+                .formats()
+                .ok_or(anyhow::anyhow!("Unknown supported formats"))?
+                .next()
+                .ok_or(anyhow::anyhow!("Failed to get sample format"))?,
+        );
+        encoder.set_bit_rate(decoder.bit_rate());
+        encoder.set_max_bit_rate(decoder.max_bit_rate());
+        encoder.set_time_base(Rational(1, rate));
+        output_stream.set_time_base(Rational(1, rate));
 
-        let opened_encoder = encoder.open_with(opts)?;
+        let opened_encoder = encoder.open_as(codec)?;
         output_stream.set_parameters(&opened_encoder);
 
+        let filter_graph = Self::filter_graph(filter_spec, &decoder, &opened_encoder)?;
+
         Ok(Self {
             output_stream_index,
             decoder,
             encoder: opened_encoder,
+            filter_graph,
             input_time_base: input_stream.time_base(),
             start_sec,
         })
     }
-}
 
-impl Transcoder for VideoTranscoder {
+    fn filter_graph(
+        spec: &str,
+        decoder: &codec::decoder::Audio,
+        encoder: &codec::encoder::Audio,
+    ) -> anyhow::Result<filter::Graph> {
+        let mut filter_graph = filter::Graph::new();
+
+        let args = format!(
+            "time_base={}:sample_rate={}:sample_fmt={}:channel_layout=0x{:x}",
+            decoder.time_base(),
+            decoder.rate(),
+            decoder.format().name(),
+            decoder.channel_layout().bits()
+        );
+
+        filter_graph.add(
+            &filter::find("abuffer").ok_or(anyhow::anyhow!("Failed to find filter"))?,
+            "in",
+            &args,
+        )?;
+        filter_graph.add(
+            &filter::find("abuffersink").ok_or(anyhow::anyhow!("Failed to find filter"))?,
+            "out",
+            "",
+        )?;
+
+        {
+            let mut out = filter_graph
+                .get("out")
+                .ok_or(anyhow::anyhow!("Failed to get filter"))?;
+            out.set_sample_format(encoder.format());
+            out.set_channel_layout(encoder.channel_layout());
+            out.set_sample_rate(encoder.rate());
+        }
+
+        filter_graph.output("in", 0)?.input("out", 0)?.parse(spec)?;
+        filter_graph.validate()?;
+
+        log::debug!("Filter graph: {}", filter_graph.dump());
+
+        if let Some(codec) = encoder.codec() {
+            if !codec
+                .capabilities()
+                .contains(ffmpeg::codec::capabilities::Capabilities::VARIABLE_FRAME_SIZE)
+            {
+                filter_graph
+                    .get("out")
+                    .ok_or(anyhow::anyhow!("Failed to get filter"))?
+                    .sink()
+                    .set_frame_size(encoder.frame_size());
+            }
+        }
+
+        Ok(filter_graph)
+    }
+
+    fn add_frame_to_filter_graph(&mut self, frame: &Frame) -> anyhow::Result<()> {
+        self.filter_graph
+            .get("in")
+            .ok_or(anyhow::anyhow!("Failed to get filter"))?
+            .source()
+            .add(frame)
+            .map_err(|e| anyhow::anyhow!(e))
+    }
+}
+
+impl Transcoder for AudioTranscoder {
+    fn output_stream_index(&self) -> usize {
+        self.output_stream_index
+    }
+
+    fn flush_filter_graph(&mut self) -> anyhow::Result<()> {
+        self.filter_graph
+            .get("in")
+            .ok_or(anyhow::anyhow!("Failed to get filter"))?
+            .source()
+            .flush()
+            .map_err(|e| anyhow::anyhow!(e))
+    }
+
+    fn receive_and_process_filtered_frames(
+        &mut self,
+        output: &mut format::context::Output,
+        output_stream_time_base: Rational,
+    ) -> anyhow::Result<()> {
+        let mut frame = Audio::empty();
+        let mut out = self
+            .filter_graph
+            .get("out")
+            .ok_or(anyhow::anyhow!("Failed to get filter"))?;
+        let filtered_time_base = out.sink().time_base();
+        let mut frames = Vec::new();
+        while out.sink().frame(&mut frame).is_ok() {
+            frame.set_pts(
+                frame
+                    .pts()
+                    .map(|pts| pts.rescale(filtered_time_base, self.encoder.time_base())),
+            );
+            frames.push(frame.clone());
+        }
+        for frame in frames {
+            self.send_frame_to_encoder(FrameWrapper::Audio(&frame))?;
+            self.receive_and_process_encoded_packets(output, output_stream_time_base)?;
+        }
+        Ok(())
+    }
+
     fn send_packet_to_decoder(&mut self, packet: &Packet) -> anyhow::Result<()> {
         self.decoder
             .send_packet(packet)
@@ -255,21 +2206,20 @@ impl Transcoder for VideoTranscoder {
         output: &mut format::context::Output,
         output_stream_time_base: Rational,
     ) -> anyhow::Result<()> {
-        let mut frame = Video::empty();
+        let mut frame = Audio::empty();
         let start_pts = self.start_sec.rescale((1, 1), self.input_time_base);
         while self.decoder.receive_frame(&mut frame).is_ok() {
             let timestamp = frame.timestamp().ok_or(anyhow::anyhow!("No timestamp"))?;
             frame.set_pts(Some(timestamp - start_pts));
-            frame.set_kind(picture::Type::None);
-            self.send_frame_to_encoder(FrameWrapper::Video(&frame))?;
-            self.receive_and_process_encoded_packets(output, output_stream_time_base)?;
+            self.add_frame_to_filter_graph(&frame)?;
+            self.receive_and_process_filtered_frames(output, output_stream_time_base)?;
         }
         Ok(())
     }
 
     fn send_frame_to_encoder(&mut self, frame_wrapper: FrameWrapper) -> anyhow::Result<()> {
         self.encoder
-            .send_frame(frame_wrapper.as_video()?)
+            .send_frame(frame_wrapper.as_audio()?)
             .map_err(anyhow::Error::from)
     }
 
@@ -285,103 +2235,582 @@ impl Transcoder for VideoTranscoder {
         let mut packet = Packet::empty();
         while self.encoder.receive_packet(&mut packet).is_ok() {
             packet.set_stream(self.output_stream_index);
-            packet.rescale_ts(self.input_time_base, output_stream_time_base);
+            packet.rescale_ts(self.encoder.time_base(), output_stream_time_base);
             packet.write_interleaved(output)?;
         }
         Ok(())
     }
 }
 
-struct AudioTranscoder {
-    output_stream_index: usize,
-    decoder: decoder::Audio,
-    encoder: encoder::Audio,
-    filter_graph: filter::Graph,
-    input_time_base: Rational,
+/// The chapters of the input that overlap the range, cut to it and timed from its start.
+fn source_chapters(
+    input: &format::context::Input,
     start_sec: i64,
+    output_msec: i64,
+) -> Vec<Chapter> {
+    let range_start_msec = start_sec * 1000;
+    input
+        .chapters()
+        .filter_map(|chapter| {
+            let msec = |ts: i64| ts.rescale(chapter.time_base(), (1, 1000)) - range_start_msec;
+            let start_msec = msec(chapter.start()).clamp(0, output_msec);
+            let end_msec = msec(chapter.end()).clamp(0, output_msec);
+            (end_msec > start_msec).then(|| Chapter {
+                start_msec,
+                end_msec,
+                title: chapter
+                    .metadata()
+                    .get("title")
+                    .unwrap_or_default()
+                    .to_owned(),
+            })
+        })
+        .collect()
 }
 
-impl AudioTranscoder {
+pub(crate) fn transcode(
+    input_path: &Path,
+    commentary_tracks: &[CommentaryTrack],
+    output_path: &Path,
+    start_sec: i64,
+    duration_sec: i64,
+    mix: Mix,
+    encoding: &Encoding,
+) -> anyhow::Result<Vec<Chapter>> {
+    encoding.validate()?;
+    let mut input = format::input(input_path)?;
+    // HLS names a media playlist per variant and writes the master playlist given in its options.
+    let muxer_path = match encoding.format {
+        OutputFormat::Hls => output_path.with_file_name("stream_%v.m3u8"),
+        _ => output_path.to_owned(),
+    };
+    if let Some(dir) = output_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut output = format::output_as(&muxer_path, encoding.format.muxer())?;
+    let mut transcoders: HashMap<i32, Vec<Box<dyn Transcoder>>> = HashMap::new();
+
+    let plan = OverlayPlan::new(mix, duration_sec * 1000, commentary_tracks)?;
+
+    let overlay_audio_filter_specs = if commentary_tracks.is_empty() {
+        vec![("anull".to_owned(), None)]
+    } else {
+        commentary_tracks
+            .iter()
+            .enumerate()
+            .map(|(track_index, track)| {
+                Ok((
+                    plan.filter_spec(track_index, track)?,
+                    track.language.as_deref(),
+                ))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?
+    };
+
+    for (filter_spec, language) in &overlay_audio_filter_specs {
+        println!(
+            "Overlay audio filter spec ({}): {}",
+            language.unwrap_or("und"),
+            filter_spec
+        );
+    }
+
+    format::context::input::dump(
+        &input,
+        0,
+        Some(input_path.to_str().ok_or(anyhow::anyhow!("Invalid path"))?),
+    );
+
+    let start_pos = start_sec.rescale((1, 1), rescale::TIME_BASE);
+    input.seek(start_pos, ..start_pos)?;
+
+    let video_index = encoding.streams.stream_index(&input, media::Type::Video)?;
+    let audio_index = encoding.streams.stream_index(&input, media::Type::Audio)?;
+    let subtitle_indices = if encoding
+        .streams
+        .passthrough
+        .contains(&Passthrough::Subtitles)
+    {
+        encoding.streams.subtitle_indices(&input)?
+    } else {
+        Vec::new()
+    };
+    let mut stream_mapping = vec![0_i32; input.nb_streams() as _];
+    let mut input_stream_time_base = vec![Rational(0, 0); input.nb_streams() as _];
+    // Where each copied stream starts, once its first packet in the range is found.
+    let mut copy_start_pts = vec![None; input.nb_streams() as _];
+    let mut output_stream_index = 0;
+    let mut video_count = 0;
+    let mut audio_streams = Vec::new();
+    let mut subtitle_conversions = HashMap::new();
+    for (ist_index, ist) in input.streams().enumerate() {
+        let ist_medium = ist.parameters().medium();
+        let selected = Some(ist_index) == video_index || Some(ist_index) == audio_index;
+        let passes = |passthrough| encoding.streams.passthrough.contains(&passthrough);
+        // What the copied stream is stored as, if it is copied at all.
+        let copied_codec = match ist_medium {
+            _ if selected => None,
+            media::Type::Audio | media::Type::Video if encoding.streams.keep_others => {
+                Some(ist.parameters().id())
+            }
+            media::Type::Subtitle if subtitle_indices.contains(&ist_index) => {
+                encoding.format.subtitle_codec(ist.parameters().id())
+            }
+            media::Type::Attachment
+                if passes(Passthrough::Attachments) && encoding.format == OutputFormat::Mkv =>
+            {
+                Some(ist.parameters().id())
+            }
+            media::Type::Data if passes(Passthrough::Data) && !encoding.format.is_segmented() => {
+                Some(ist.parameters().id())
+            }
+            _ => None,
+        };
+        if !selected && copied_codec.is_none() {
+            if ist_medium == media::Type::Subtitle && subtitle_indices.contains(&ist_index) {
+                println!(
+                    "Dropping {} subtitles, which {} cannot store",
+                    ist.parameters().id().name(),
+                    value_name(&encoding.format)
+                );
+            }
+            stream_mapping[ist_index] = -1;
+            continue;
+        }
+        stream_mapping[ist_index] = output_stream_index;
+        input_stream_time_base[ist_index] = ist.time_base();
+        if let Some(codec_id) = copied_codec {
+            // Copied as is, without the commentary.
+            let mut ost = output.add_stream(encoder::find(codec::Id::None))?;
+            if codec_id == ist.parameters().id() {
+                ost.set_parameters(ist.parameters());
+                // The tag of the source container may mean something else, or nothing, in the
+                // output one, so let the muxer choose its own.
+                unsafe {
+                    (*ost.parameters().as_mut_ptr()).codec_tag = 0;
+                }
+            } else {
+                let codec = encoder::find(codec_id).ok_or(anyhow::anyhow!(
+                    "No {} encoder in this FFmpeg build",
+                    codec_id.name()
+                ))?;
+                ost.set_parameters(&codec::context::Context::new_with_codec(codec));
+                if codec_id == codec::Id::MOV_TEXT {
+                    // Players need the sample description that the encoder would have made.
+                    set_extradata(&mut ost, &subtitle::mov_text_extradata())?;
+                }
+                subtitle_conversions.insert(ist_index, (ist.parameters().id(), codec_id));
+            }
+            ost.set_time_base(ist.time_base());
+            ost.set_metadata(ist.metadata().to_owned());
+            output_stream_index += 1;
+        } else if ist_medium == media::Type::Video {
+            let renditions = match encoding.renditions.as_slice() {
+                [] => vec![None],
+                renditions => renditions.iter().copied().map(Some).collect(),
+            };
+            let mut video_transcoders: Vec<Box<dyn Transcoder>> = Vec::new();
+            for rendition in renditions {
+                video_transcoders.push(Box::new(VideoTranscoder::new(
+                    &ist,
+                    &mut output,
+                    output_stream_index as _,
+                    start_sec,
+                    plan.hold_msec,
+                    encoding,
+                    rendition,
+                )?));
+                output_stream_index += 1;
+                video_count += 1;
+            }
+            transcoders.insert(ist_index as i32, video_transcoders);
+        } else if ist_medium == media::Type::Audio {
+            // One output track per commentary language, each mixed from the same source.
+            let mut audio_transcoders: Vec<Box<dyn Transcoder>> = Vec::new();
+            for (filter_spec, language) in &overlay_audio_filter_specs {
+                audio_transcoders.push(Box::new(AudioTranscoder::new(
+                    &ist,
+                    &mut output,
+                    output_stream_index as _,
+                    filter_spec.as_str(),
+                    *language,
+                    start_sec,
+                    encoding.audio_codec(),
+                )?));
+                audio_streams.push((output_stream_index as usize, *language));
+                output_stream_index += 1;
+            }
+            transcoders.insert(ist_index as i32, audio_transcoders);
+        }
+    }
+
+    output.set_metadata(input.metadata().to_owned());
+    // The commentary may run the output past the range, and the end of the source cut it short.
+    let source_msec = (input.duration() > 0)
+        .then(|| input.duration().rescale(rescale::TIME_BASE, (1, 1000)) - start_sec * 1000);
+    let output_msec = plan
+        .hold_msec
+        .unwrap_or(source_msec.map_or(plan.read_msec, |source_msec| {
+            plan.read_msec.min(source_msec)
+        }));
+    let chapters = match &encoding.chapters {
+        Some(chapters) => chapters
+            .iter()
+            .filter(|chapter| chapter.start_msec < output_msec)
+            .map(|chapter| Chapter {
+                end_msec: chapter.end_msec.min(output_msec),
+                ..chapter.clone()
+            })
+            .collect(),
+        None if encoding
+            .streams
+            .passthrough
+            .contains(&Passthrough::Chapters) =>
+        {
+            source_chapters(&input, start_sec, output_msec)
+        }
+        None => Vec::new(),
+    };
+    if encoding.format.supports_chapters() {
+        for (index, chapter) in chapters.iter().enumerate() {
+            output.add_chapter(
+                index as i64,
+                (1, 1000),
+                chapter.start_msec,
+                chapter.end_msec,
+                &chapter.title,
+            )?;
+        }
+    }
+    format::context::output::dump(
+        &output,
+        0,
+        Some(
+            output_path
+                .to_str()
+                .ok_or(anyhow::anyhow!("Invalid path"))?,
+        ),
+    );
+    output.write_header_with(encoding.muxer_options(
+        output_path,
+        &audio_streams,
+        video_count,
+    )?)?;
+
+    let output_stream_time_base = output
+        .streams()
+        .map(|stream| stream.time_base())
+        .collect::<Vec<_>>();
+
+    for (ist, mut packet) in input.packets() {
+        let ist_index = ist.index();
+        let ost_index = stream_mapping[ist_index];
+        if ost_index < 0 {
+            continue;
+        }
+        let end_pts = (start_sec * 1000 + plan.read_msec).rescale((1, 1000), ist.time_base());
+        let Some(pts) = packet.pts() else {
+            continue;
+        };
+        if pts >= end_pts {
+            break;
+        }
+
+        match transcoders.get_mut(&(ist_index as i32)) {
+            Some(stream_transcoders) => {
+                for transcoder in stream_transcoders.iter_mut() {
+                    let ost_time_base = output_stream_time_base[transcoder.output_stream_index()];
+                    transcoder.send_packet_to_decoder(&packet)?;
+                    transcoder.receive_and_process_decoded_frames(&mut output, ost_time_base)?;
+                }
+            }
+            None => {
+                let ost_time_base = *output_stream_time_base
+                    .get(ost_index as usize)
+                    .ok_or(anyhow::anyhow!(Error::StreamNotFound))?;
+                // Shift copied packets like the transcoded frames, to start at the range.
+                let start_pts = start_sec.rescale((1, 1), input_stream_time_base[ist_index]);
+                // Copied video can only start at a keyframe, and the pictures decoded after it
+                // but shown before it refer to ones that were left out.
+                let first_pts = match copy_start_pts[ist_index] {
+                    Some(first_pts) => first_pts,
+                    None if pts >= start_pts
+                        && (ist.parameters().medium() != media::Type::Video || packet.is_key()) =>
+                    {
+                        copy_start_pts[ist_index] = Some(pts);
+                        pts
+                    }
+                    None => continue,
+                };
+                if pts < first_pts {
+                    continue;
+                }
+                if let Some(&(input_codec, output_codec)) = subtitle_conversions.get(&ist_index) {
+                    let data = packet.data().unwrap_or_default();
+                    let text = match input_codec {
+                        codec::Id::MOV_TEXT => subtitle::mov_text_sample_text(data),
+                        codec::Id::ASS | codec::Id::SSA => subtitle::plain_text(data, true),
+                        _ => subtitle::plain_text(data, false),
+                    };
+                    let mut converted = Packet::copy(&match output_codec {
+                        codec::Id::MOV_TEXT => subtitle::mov_text_sample(&text),
+                        _ => text.into_bytes(),
+                    });
+                    converted.set_dts(packet.dts());
+                    converted.set_duration(packet.duration());
+                    packet = converted;
+                }
+                packet.set_pts(Some(pts - start_pts));
+                packet.set_dts(packet.dts().map(|dts| dts - start_pts));
+                packet.rescale_ts(input_stream_time_base[ist_index], ost_time_base);
+                packet.set_stream(ost_index as usize);
+                packet.write_interleaved(&mut output)?;
+            }
+        }
+    }
+
+    for transcoder in transcoders.values_mut().flatten() {
+        let ost_time_base = output_stream_time_base[transcoder.output_stream_index()];
+        transcoder.send_eof_to_decoder()?;
+        transcoder.receive_and_process_decoded_frames(&mut output, ost_time_base)?;
+        transcoder.hold_last_frame(&mut output, ost_time_base)?;
+        transcoder.flush_filter_graph()?;
+        transcoder.receive_and_process_filtered_frames(&mut output, ost_time_base)?;
+        transcoder.send_eof_to_encoder()?;
+        transcoder.receive_and_process_encoded_packets(&mut output, ost_time_base)?;
+    }
+
+    output.write_trailer()?;
+    drop(output);
+    if muxer_path != output_path {
+        // Opened for the muxer but never written, since HLS writes every playlist itself.
+        fs::remove_file(&muxer_path).ok();
+    }
+
+    Ok(chapters)
+}
+
+/// An input clip, written as `<path>` or `<path>@<start sec>+<duration sec>`.
+#[derive(Clone, Debug)]
+pub(crate) struct Clip {
+    pub(crate) path: PathBuf,
+    pub(crate) range: Option<(i64, i64)>,
+}
+
+impl FromStr for Clip {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        // Anything that does not end in a range is a path, which may contain '@' itself.
+        let range = value.rsplit_once('@').and_then(|(path, range)| {
+            let (start, duration) = range.split_once('+')?;
+            Some((path, (start.parse().ok()?, duration.parse().ok()?)))
+        });
+        Ok(match range {
+            Some((path, (start_sec, duration_sec))) => {
+                if start_sec < 0 || duration_sec <= 0 {
+                    anyhow::bail!("Invalid range in {}", value);
+                }
+                Self {
+                    path: PathBuf::from(path),
+                    range: Some((start_sec, duration_sec)),
+                }
+            }
+            None => Self {
+                path: PathBuf::from(value),
+                range: None,
+            },
+        })
+    }
+}
+
+const CONCAT_RATE: u32 = 48000;
+
+/// Replaces the codec extradata of an output stream.
+fn set_extradata(stream: &mut format::stream::StreamMut, extradata: &[u8]) -> anyhow::Result<()> {
+    unsafe {
+        let parameters = (*stream.as_mut_ptr()).codecpar;
+        let data = ffmpeg::ffi::av_mallocz(
+            extradata.len() + ffmpeg::ffi::AV_INPUT_BUFFER_PADDING_SIZE as usize,
+        ) as *mut u8;
+        if data.is_null() {
+            anyhow::bail!("Failed to allocate the codec extradata");
+        }
+        std::ptr::copy_nonoverlapping(extradata.as_ptr(), data, extradata.len());
+        ffmpeg::ffi::av_freep(&mut (*parameters).extradata as *mut *mut u8 as *mut _);
+        (*parameters).extradata = data;
+        (*parameters).extradata_size = extradata.len() as i32;
+    }
+    Ok(())
+}
+
+fn write_encoded_packets(
+    encoder: &mut encoder::Encoder,
+    output: &mut format::context::Output,
+    output_stream_index: usize,
+) -> anyhow::Result<()> {
+    let encoder_time_base = encoder.time_base();
+    let output_time_base = output
+        .stream(output_stream_index)
+        .map(|stream| stream.time_base())
+        .ok_or(anyhow::anyhow!(Error::StreamNotFound))?;
+    let mut packet = Packet::empty();
+    while encoder.receive_packet(&mut packet).is_ok() {
+        packet.set_stream(output_stream_index);
+        packet.rescale_ts(encoder_time_base, output_time_base);
+        packet.write_interleaved(output)?;
+    }
+    Ok(())
+}
+
+/// Joins clips into one file, scaling and padding every clip to the size of the first one and
+/// converting it to the same frame rate and audio format.
+struct Concatenator {
+    output: format::context::Output,
+    video_encoder: encoder::Video,
+    audio_encoder: encoder::Audio,
+    width: u32,
+    height: u32,
+    frame_rate: Rational,
+    /// End of the last video frame written
+    video_end_msec: i64,
+}
+
+impl Concatenator {
     fn new(
-        input_stream: &format::stream::Stream,
-        output: &mut format::context::Output,
-        output_stream_index: usize,
-        filter_spec: &str,
-        start_sec: i64,
+        width: u32,
+        height: u32,
+        frame_rate: Rational,
+        output_path: &Path,
     ) -> anyhow::Result<Self> {
+        let (width, height) = ((width / 2 * 2).max(2), (height / 2 * 2).max(2));
+        let mut output = format::output(&output_path)?;
         let global_header = output
             .format()
             .flags()
             .contains(format::Flags::GLOBAL_HEADER);
-        let codec_params = input_stream.parameters();
-        let mut decoder = codec::context::Context::from_parameters(codec_params)?
-            .decoder()
-            .audio()?;
 
+        let codec = VideoCodec::H264
+            .encoder()
+            .ok_or(anyhow::anyhow!(Error::EncoderNotFound))?;
+        let mut output_stream = output.add_stream(codec)?;
+        let mut video_encoder = codec::context::Context::new_with_codec(codec)
+            .encoder()
+            .video()?;
+        video_encoder.set_width(width);
+        video_encoder.set_height(height);
+        video_encoder.set_aspect_ratio(Rational(1, 1));
+        video_encoder.set_format(format::Pixel::YUV420P);
+        video_encoder.set_frame_rate(Some(frame_rate));
+        video_encoder.set_time_base(frame_rate.invert());
+        output_stream.set_time_base(frame_rate.invert());
         if global_header {
-            decoder.set_flags(codec::Flags::GLOBAL_HEADER);
+            video_encoder.set_flags(codec::Flags::GLOBAL_HEADER);
         }
+        // Only an intermediate for the pipeline, so keep as much quality as possible.
+        let mut options = VideoCodec::H264.options(false);
+        options.set("crf", "18");
+        let video_encoder = video_encoder.open_with(options)?;
+        output_stream.set_parameters(&video_encoder);
 
         let codec = encoder::find(codec::Id::AAC)
             .ok_or(anyhow::anyhow!(Error::EncoderNotFound))?
             .audio()?;
         let mut output_stream = output.add_stream(codec)?;
-        let context = codec::context::Context::from_parameters(output_stream.parameters())?;
-        let mut encoder = context.encoder().audio()?;
-
+        let mut audio_encoder =
+            codec::context::Context::from_parameters(output_stream.parameters())?
+                .encoder()
+                .audio()?;
+        audio_encoder.set_channel_layout(channel_layout::ChannelLayout::STEREO);
+        audio_encoder.set_rate(CONCAT_RATE as i32);
+        audio_encoder.set_format(format::Sample::F32(format::sample::Type::Planar));
+        audio_encoder.set_bit_rate(192_000);
+        audio_encoder.set_time_base(Rational(1, CONCAT_RATE as i32));
+        output_stream.set_time_base(Rational(1, CONCAT_RATE as i32));
         if global_header {
-            encoder.set_flags(codec::Flags::GLOBAL_HEADER);
+            audio_encoder.set_flags(codec::Flags::GLOBAL_HEADER);
         }
+        let audio_encoder = audio_encoder.open_as(codec)?;
+        output_stream.set_parameters(&audio_encoder);
 
-        let channel_layout = codec
-            .channel_layouts()
-            .map(|layouts| layouts.best(decoder.channel_layout().channels()))
-            .unwrap_or(channel_layout::ChannelLayout::STEREO);
-
-        encoder.set_channel_layout(channel_layout);
-        encoder.set_rate(decoder.rate() as _);
-        encoder.set_format(
-            codec
-                .formats()
-                .ok_or(anyhow::anyhow!("Unknown supported formats"))?
-                .next()
-                .ok_or(anyhow::anyhow!("Failed to get sample format"))?,
-        );
-        encoder.set_bit_rate(decoder.bit_rate());
-        encoder.set_max_bit_rate(decoder.max_bit_rate());
-        encoder.set_time_base(decoder.time_base());
-        output_stream.set_time_base(decoder.time_base());
-
-        let opened_encoder = encoder.open_as(codec)?;
-        output_stream.set_parameters(&opened_encoder);
-
-        let filter_graph = Self::filter_graph(filter_spec, &decoder, &opened_encoder)?;
-
+        output.write_header()?;
         Ok(Self {
-            output_stream_index,
-            decoder,
-            encoder: opened_encoder,
-            filter_graph,
-            input_time_base: input_stream.time_base(),
-            start_sec,
+            output,
+            video_encoder,
+            audio_encoder,
+            width,
+            height,
+            frame_rate,
+            video_end_msec: 0,
         })
     }
 
-    fn filter_graph(
-        spec: &str,
-        decoder: &codec::decoder::Audio,
-        encoder: &codec::encoder::Audio,
+    fn video_filter_graph(
+        &self,
+        (width, height): (u32, u32),
+        pixel_format: format::Pixel,
+        aspect_ratio: Rational,
+        time_base: Rational,
     ) -> anyhow::Result<filter::Graph> {
         let mut filter_graph = filter::Graph::new();
+        let aspect_ratio = Some(aspect_ratio)
+            .filter(|ratio| ratio.numerator() > 0)
+            .unwrap_or(Rational(1, 1));
+        let args = format!(
+            "video_size={}x{}:pix_fmt={}:time_base={}:pixel_aspect={}",
+            width,
+            height,
+            pixel_format.name(),
+            time_base,
+            aspect_ratio
+        );
+        filter_graph.add(
+            &filter::find("buffer").ok_or(anyhow::anyhow!("Failed to find filter"))?,
+            "in",
+            &args,
+        )?;
+        filter_graph.add(
+            &filter::find("buffersink").ok_or(anyhow::anyhow!("Failed to find filter"))?,
+            "out",
+            "",
+        )?;
+        filter_graph
+            .get("out")
+            .ok_or(anyhow::anyhow!("Failed to get filter"))?
+            .set_pixel_format(format::Pixel::YUV420P);
+
+        let spec = format!(
+            "scale={w}:{h}:force_original_aspect_ratio=decrease,pad={w}:{h}:(ow-iw)/2:(oh-ih)/2,setsar=1,fps={fps},format=yuv420p",
+            w = self.width,
+            h = self.height,
+            fps = self.frame_rate
+        );
+        filter_graph
+            .output("in", 0)?
+            .input("out", 0)?
+            .parse(&spec)?;
+        filter_graph.validate()?;
+        Ok(filter_graph)
+    }
 
+    fn audio_filter_graph(
+        &self,
+        decoder: &decoder::Audio,
+        time_base: Rational,
+    ) -> anyhow::Result<filter::Graph> {
+        let mut filter_graph = filter::Graph::new();
+        let channel_layout = if decoder.channel_layout().is_empty() {
+            channel_layout::ChannelLayout::default(decoder.channels() as i32)
+        } else {
+            decoder.channel_layout()
+        };
         let args = format!(
             "time_base={}:sample_rate={}:sample_fmt={}:channel_layout=0x{:x}",
-            decoder.time_base(),
+            time_base,
             decoder.rate(),
             decoder.format().name(),
-            decoder.channel_layout().bits()
+            channel_layout.bits()
         );
-
         filter_graph.add(
             &filter::find("abuffer").ok_or(anyhow::anyhow!("Failed to find filter"))?,
             "in",
@@ -392,252 +2821,943 @@ impl AudioTranscoder {
             "out",
             "",
         )?;
-
         {
             let mut out = filter_graph
                 .get("out")
                 .ok_or(anyhow::anyhow!("Failed to get filter"))?;
-            out.set_sample_format(encoder.format());
-            out.set_channel_layout(encoder.channel_layout());
-            out.set_sample_rate(encoder.rate());
+            out.set_sample_format(self.audio_encoder.format());
+            out.set_channel_layout(self.audio_encoder.channel_layout());
+            out.set_sample_rate(self.audio_encoder.rate());
+        }
+        filter_graph
+            .output("in", 0)?
+            .input("out", 0)?
+            .parse(&format!(
+                "aresample={},aformat=sample_fmts=fltp:channel_layouts=stereo",
+                CONCAT_RATE
+            ))?;
+        filter_graph.validate()?;
+        filter_graph
+            .get("out")
+            .ok_or(anyhow::anyhow!("Failed to get filter"))?
+            .sink()
+            .set_frame_size(self.audio_encoder.frame_size());
+        Ok(filter_graph)
+    }
+
+    /// Encodes what the filter graph has ready, shifted to start at `offset_msec`.
+    fn drain_video(
+        &mut self,
+        filter_graph: &mut filter::Graph,
+        offset_msec: i64,
+    ) -> anyhow::Result<()> {
+        let mut out = filter_graph
+            .get("out")
+            .ok_or(anyhow::anyhow!("Failed to get filter"))?;
+        let filtered_time_base = out.sink().time_base();
+        let encoder_time_base = self.video_encoder.time_base();
+        let mut frame = Video::empty();
+        while out.sink().frame(&mut frame).is_ok() {
+            let pts = frame.pts().ok_or(anyhow::anyhow!("No pts"))?;
+            let pts = pts.rescale(filtered_time_base, encoder_time_base)
+                + offset_msec.rescale((1, 1000), encoder_time_base);
+            frame.set_pts(Some(pts));
+            // The encoder counts in frames.
+            self.video_end_msec = self
+                .video_end_msec
+                .max((pts + 1).rescale(encoder_time_base, (1, 1000)));
+            frame.set_kind(picture::Type::None);
+            self.video_encoder.send_frame(&frame)?;
+            write_encoded_packets(&mut self.video_encoder, &mut self.output, 0)?;
+        }
+        Ok(())
+    }
+
+    fn drain_audio(
+        &mut self,
+        filter_graph: &mut filter::Graph,
+        offset_msec: i64,
+    ) -> anyhow::Result<()> {
+        let mut out = filter_graph
+            .get("out")
+            .ok_or(anyhow::anyhow!("Failed to get filter"))?;
+        let filtered_time_base = out.sink().time_base();
+        let encoder_time_base = self.audio_encoder.time_base();
+        let mut frame = Audio::empty();
+        while out.sink().frame(&mut frame).is_ok() {
+            let pts = frame.pts().ok_or(anyhow::anyhow!("No pts"))?;
+            frame.set_pts(Some(
+                pts.rescale(filtered_time_base, encoder_time_base)
+                    + offset_msec.rescale((1, 1000), encoder_time_base),
+            ));
+            self.audio_encoder.send_frame(&frame)?;
+            write_encoded_packets(&mut self.audio_encoder, &mut self.output, 1)?;
+        }
+        Ok(())
+    }
+
+    fn silence(&mut self, from_msec: i64, to_msec: i64) -> anyhow::Result<()> {
+        let frame_size = (self.audio_encoder.frame_size() as i64).max(1);
+        let encoder_time_base = self.audio_encoder.time_base();
+        let end_pts = to_msec.rescale((1, 1000), encoder_time_base);
+        let mut pts = from_msec.rescale((1, 1000), encoder_time_base);
+        while pts < end_pts {
+            let mut frame = Audio::new(
+                self.audio_encoder.format(),
+                frame_size as usize,
+                channel_layout::ChannelLayout::STEREO,
+            );
+            frame.set_rate(CONCAT_RATE);
+            for channel in 0..frame.planes() {
+                frame.plane_mut::<f32>(channel).fill(0.0);
+            }
+            frame.set_pts(Some(pts));
+            self.audio_encoder.send_frame(&frame)?;
+            write_encoded_packets(&mut self.audio_encoder, &mut self.output, 1)?;
+            pts += frame_size;
         }
+        Ok(())
+    }
 
-        filter_graph.output("in", 0)?.input("out", 0)?.parse(spec)?;
-        filter_graph.validate()?;
+    /// Appends a clip at `offset_msec` and returns the length of the video written for it.
+    fn append(&mut self, clip: &Clip, offset_msec: i64) -> anyhow::Result<i64> {
+        let mut input = format::input(&clip.path)?;
+        let file_msec = duration_msec(&clip.path)?;
+        let (start_sec, clip_msec) = match clip.range {
+            // A range running past the end of the file ends with it.
+            Some((start_sec, duration_sec)) => (
+                start_sec,
+                (duration_sec * 1000).min(file_msec - start_sec * 1000),
+            ),
+            None => (0, file_msec),
+        };
+        if clip_msec <= 0 {
+            anyhow::bail!("{} has nothing in its range", clip.path.display());
+        }
+        let start_pos = start_sec.rescale((1, 1), rescale::TIME_BASE);
+        input.seek(start_pos, ..start_pos)?;
+
+        let video_stream = input
+            .streams()
+            .best(media::Type::Video)
+            .ok_or(anyhow::anyhow!(Error::StreamNotFound))?;
+        let video_index = video_stream.index();
+        let video_time_base = video_stream.time_base();
+        let mut video_decoder =
+            codec::context::Context::from_parameters(video_stream.parameters())?
+                .decoder()
+                .video()?;
+        let mut video_graph = self.video_filter_graph(
+            (video_decoder.width(), video_decoder.height()),
+            video_decoder.format(),
+            video_decoder.aspect_ratio(),
+            video_time_base,
+        )?;
 
-        println!("Filter graph: {}", filter_graph.dump());
+        let mut audio = match input.streams().best(media::Type::Audio) {
+            Some(audio_stream) => {
+                let decoder = codec::context::Context::from_parameters(audio_stream.parameters())?
+                    .decoder()
+                    .audio()?;
+                let filter_graph = self.audio_filter_graph(&decoder, audio_stream.time_base())?;
+                Some((
+                    audio_stream.index(),
+                    audio_stream.time_base(),
+                    decoder,
+                    filter_graph,
+                ))
+            }
+            None => None,
+        };
 
-        if let Some(codec) = encoder.codec() {
-            if !codec
-                .capabilities()
-                .contains(ffmpeg::codec::capabilities::Capabilities::VARIABLE_FRAME_SIZE)
-            {
+        let range_pts = |time_base: Rational| {
+            (
+                start_sec.rescale((1, 1), time_base),
+                (start_sec * 1000 + clip_msec).rescale((1, 1000), time_base),
+            )
+        };
+        let receive_video = |this: &mut Self,
+                             decoder: &mut decoder::Video,
+                             filter_graph: &mut filter::Graph|
+         -> anyhow::Result<()> {
+            let (start_pts, end_pts) = range_pts(video_time_base);
+            let mut decoded = Video::empty();
+            while decoder.receive_frame(&mut decoded).is_ok() {
+                let pts = decoded.timestamp().ok_or(anyhow::anyhow!("No timestamp"))?;
+                if pts < start_pts || pts >= end_pts {
+                    continue;
+                }
+                decoded.set_pts(Some(pts - start_pts));
                 filter_graph
-                    .get("out")
+                    .get("in")
                     .ok_or(anyhow::anyhow!("Failed to get filter"))?
-                    .sink()
-                    .set_frame_size(encoder.frame_size());
+                    .source()
+                    .add(&decoded)?;
+                this.drain_video(filter_graph, offset_msec)?;
             }
-        }
+            Ok(())
+        };
+        let receive_audio = |this: &mut Self,
+                             time_base: Rational,
+                             decoder: &mut decoder::Audio,
+                             filter_graph: &mut filter::Graph|
+         -> anyhow::Result<()> {
+            let (start_pts, end_pts) = range_pts(time_base);
+            let mut decoded = Audio::empty();
+            while decoder.receive_frame(&mut decoded).is_ok() {
+                let pts = decoded.timestamp().ok_or(anyhow::anyhow!("No timestamp"))?;
+                if pts < start_pts || pts >= end_pts {
+                    continue;
+                }
+                decoded.set_pts(Some(pts - start_pts));
+                filter_graph
+                    .get("in")
+                    .ok_or(anyhow::anyhow!("Failed to get filter"))?
+                    .source()
+                    .add(&decoded)?;
+                this.drain_audio(filter_graph, offset_msec)?;
+            }
+            Ok(())
+        };
 
-        Ok(filter_graph)
-    }
+        let end_pos = (start_sec * 1000 + clip_msec).rescale((1, 1000), rescale::TIME_BASE);
+        for (stream, packet) in input.packets() {
+            let past_end = packet
+                .pts()
+                .is_some_and(|pts| pts.rescale(stream.time_base(), rescale::TIME_BASE) >= end_pos);
+            if stream.index() == video_index {
+                if past_end {
+                    continue;
+                }
+                video_decoder.send_packet(&packet)?;
+                receive_video(self, &mut video_decoder, &mut video_graph)?;
+            } else if let Some((audio_index, time_base, decoder, filter_graph)) = &mut audio {
+                if stream.index() == *audio_index && !past_end {
+                    decoder.send_packet(&packet)?;
+                    receive_audio(self, *time_base, decoder, filter_graph)?;
+                }
+            }
+        }
 
-    fn add_frame_to_filter_graph(&mut self, frame: &Frame) -> anyhow::Result<()> {
-        self.filter_graph
+        video_decoder.send_eof()?;
+        receive_video(self, &mut video_decoder, &mut video_graph)?;
+        video_graph
             .get("in")
             .ok_or(anyhow::anyhow!("Failed to get filter"))?
             .source()
-            .add(frame)
-            .map_err(|e| anyhow::anyhow!(e))
+            .flush()?;
+        self.drain_video(&mut video_graph, offset_msec)?;
+        match &mut audio {
+            Some((_, time_base, decoder, filter_graph)) => {
+                decoder.send_eof()?;
+                receive_audio(self, *time_base, decoder, filter_graph)?;
+                filter_graph
+                    .get("in")
+                    .ok_or(anyhow::anyhow!("Failed to get filter"))?
+                    .source()
+                    .flush()?;
+                self.drain_audio(filter_graph, offset_msec)?;
+            }
+            None => self.silence(offset_msec, self.video_end_msec)?,
+        }
+        let written_msec = self.video_end_msec - offset_msec;
+        if written_msec <= 0 {
+            anyhow::bail!("No video in the range of {}", clip.path.display());
+        }
+        Ok(written_msec)
     }
-}
 
-impl Transcoder for AudioTranscoder {
-    fn flush_filter_graph(&mut self) -> anyhow::Result<()> {
-        self.filter_graph
+    /// Shows an image from `offset_msec` for `duration_msec`, over silence.
+    fn append_image(
+        &mut self,
+        image: &image::RgbImage,
+        offset_msec: i64,
+        duration_msec: i64,
+    ) -> anyhow::Result<()> {
+        let time_base = Rational(1, 1000);
+        let mut filter_graph = self.video_filter_graph(
+            image.dimensions(),
+            format::Pixel::RGB24,
+            Rational(1, 1),
+            time_base,
+        )?;
+        let frame = rgb_frame(image);
+        let frame_msec = 1_i64.rescale(self.frame_rate.invert(), time_base).max(1);
+        let mut pts = 0;
+        while pts < duration_msec {
+            let mut frame = frame.clone();
+            frame.set_pts(Some(pts));
+            filter_graph
+                .get("in")
+                .ok_or(anyhow::anyhow!("Failed to get filter"))?
+                .source()
+                .add(&frame)?;
+            self.drain_video(&mut filter_graph, offset_msec)?;
+            pts += frame_msec;
+        }
+        filter_graph
             .get("in")
             .ok_or(anyhow::anyhow!("Failed to get filter"))?
             .source()
-            .flush()
-            .map_err(|e| anyhow::anyhow!(e))
+            .flush()?;
+        self.drain_video(&mut filter_graph, offset_msec)?;
+        self.silence(offset_msec, offset_msec + duration_msec)
     }
 
-    fn receive_and_process_filtered_frames(
-        &mut self,
-        output: &mut format::context::Output,
-        output_stream_time_base: Rational,
-    ) -> anyhow::Result<()> {
-        let mut frame = Audio::empty();
-        while self
-            .filter_graph
-            .get("out")
-            .ok_or(anyhow::anyhow!("Failed to get filter"))?
-            .sink()
-            .frame(&mut frame)
-            .is_ok()
-        {
-            self.send_frame_to_encoder(FrameWrapper::Audio(&frame))?;
-            self.receive_and_process_encoded_packets(output, output_stream_time_base)?;
-        }
+    fn finish(mut self) -> anyhow::Result<()> {
+        self.video_encoder.send_eof()?;
+        write_encoded_packets(&mut self.video_encoder, &mut self.output, 0)?;
+        self.audio_encoder.send_eof()?;
+        write_encoded_packets(&mut self.audio_encoder, &mut self.output, 1)?;
+        self.output.write_trailer()?;
         Ok(())
     }
+}
 
-    fn send_packet_to_decoder(&mut self, packet: &Packet) -> anyhow::Result<()> {
-        self.decoder
-            .send_packet(packet)
-            .map_err(anyhow::Error::from)
+/// Joins the clips into one video and returns the length of each clip in it.
+pub(crate) fn concat(clips: &[Clip], output_path: &Path) -> anyhow::Result<Vec<i64>> {
+    let first_clip = clips.first().ok_or(anyhow::anyhow!("No clips to join"))?;
+    // Checked up front so that a bad range fails before anything is joined.
+    for clip in clips {
+        if let Some((start_sec, _)) = clip.range {
+            let file_msec = duration_msec(&clip.path)?;
+            if start_sec * 1000 >= file_msec {
+                anyhow::bail!(
+                    "{} starts at {} s, past the end of the {} ms video",
+                    clip.path.display(),
+                    start_sec,
+                    file_msec
+                );
+            }
+        }
     }
-
-    fn send_eof_to_decoder(&mut self) -> anyhow::Result<()> {
-        self.decoder.send_eof().map_err(anyhow::Error::from)
+    let input = format::input(&first_clip.path)?;
+    let video_stream = input
+        .streams()
+        .best(media::Type::Video)
+        .ok_or(anyhow::anyhow!(Error::StreamNotFound))?;
+    let decoder = codec::context::Context::from_parameters(video_stream.parameters())?
+        .decoder()
+        .video()?;
+    let frame_rate = Some(video_stream.avg_frame_rate())
+        .filter(|rate| rate.numerator() > 0)
+        .unwrap_or(Rational(30, 1));
+    let mut concatenator =
+        Concatenator::new(decoder.width(), decoder.height(), frame_rate, output_path)?;
+    let mut clip_msecs = Vec::new();
+    let mut offset_msec = 0;
+    for clip in clips {
+        println!("Joining {} at {} ms", clip.path.display(), offset_msec);
+        let clip_msec = concatenator.append(clip, offset_msec)?;
+        clip_msecs.push(clip_msec);
+        offset_msec += clip_msec;
     }
+    concatenator.finish()?;
+    Ok(clip_msecs)
+}
 
-    fn receive_and_process_decoded_frames(
-        &mut self,
-        output: &mut format::context::Output,
-        output_stream_time_base: Rational,
-    ) -> anyhow::Result<()> {
-        let mut frame = Audio::empty();
-        let start_pts = self.start_sec.rescale((1, 1), self.input_time_base);
-        while self.decoder.receive_frame(&mut frame).is_ok() {
-            let timestamp = frame.timestamp().ok_or(anyhow::anyhow!("No timestamp"))?;
-            frame.set_pts(Some(timestamp - start_pts));
-            self.add_frame_to_filter_graph(&frame)?;
-            self.receive_and_process_filtered_frames(output, output_stream_time_base)?;
+const IMAGE_EXTENSIONS: [&str; 8] = ["jpg", "jpeg", "png", "webp", "bmp", "gif", "tif", "tiff"];
+
+fn is_image(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .is_some_and(|extension| IMAGE_EXTENSIONS.contains(&extension.as_str()))
+}
+
+/// The images of an input that is an image, a directory of images or an image2 pattern such as
+/// `frames_%04d.png`, in order; `None` for anything else.
+pub(crate) fn image_files(input_path: &Path) -> anyhow::Result<Option<Vec<PathBuf>>> {
+    if input_path.is_dir() {
+        let mut images = Vec::new();
+        for entry in fs::read_dir(input_path)? {
+            let path = entry?.path();
+            if path.is_file() && is_image(&path) {
+                images.push(path);
+            }
         }
-        Ok(())
+        if images.is_empty() {
+            anyhow::bail!("No images in {}", input_path.display());
+        }
+        images.sort();
+        return Ok(Some(images));
     }
-
-    fn send_frame_to_encoder(&mut self, frame_wrapper: FrameWrapper) -> anyhow::Result<()> {
-        self.encoder
-            .send_frame(frame_wrapper.as_audio()?)
-            .map_err(anyhow::Error::from)
+    if !is_image(input_path) {
+        return Ok(None);
     }
+    let pattern = input_path.to_string_lossy();
+    let Some((prefix, rest)) = pattern.split_once('%') else {
+        return Ok(Some(vec![input_path.to_owned()]));
+    };
+    let Some((width, suffix)) = rest.split_once('d') else {
+        return Ok(Some(vec![input_path.to_owned()]));
+    };
+    let width: usize = width.trim_start_matches('0').parse().unwrap_or(0);
+    let image_path =
+        |number: usize| PathBuf::from(format!("{}{:0width$}{}", prefix, number, suffix));
+    // Like image2, the sequence may start anywhere from 0 to 4 and ends at the first gap.
+    let Some(first) = (0..=4).find(|&number| image_path(number).is_file()) else {
+        anyhow::bail!("No images match {}", pattern);
+    };
+    let images = (first..)
+        .map(image_path)
+        .take_while(|path| path.is_file())
+        .collect();
+    Ok(Some(images))
+}
 
-    fn send_eof_to_encoder(&mut self) -> anyhow::Result<()> {
-        self.encoder.send_eof().map_err(anyhow::Error::from)
+fn rgb_frame(image: &image::RgbImage) -> Video {
+    let (width, height) = image.dimensions();
+    let mut frame = Video::new(format::Pixel::RGB24, width, height);
+    let stride = frame.stride(0);
+    let row_len = width as usize * 3;
+    for (y, row) in image.as_raw().chunks_exact(row_len).enumerate() {
+        frame.data_mut(0)[y * stride..y * stride + row_len].copy_from_slice(row);
     }
+    frame
+}
 
-    fn receive_and_process_encoded_packets(
-        &mut self,
-        output: &mut format::context::Output,
-        output_stream_time_base: Rational,
-    ) -> anyhow::Result<()> {
-        let mut packet = Packet::empty();
-        while self.encoder.receive_packet(&mut packet).is_ok() {
-            packet.set_stream(self.output_stream_index);
-            packet.rescale_ts(self.input_time_base, output_stream_time_base);
-            packet.write_interleaved(output)?;
-        }
-        Ok(())
+/// Captures every image as a frame, `interval_msec` apart.
+pub(crate) fn capture_images(
+    images: &[PathBuf],
+    interval_msec: i64,
+    capture_dir: &Path,
+) -> anyhow::Result<Vec<CapturedFrame>> {
+    fs::create_dir_all(capture_dir)?;
+    let mut captured_frames = Vec::new();
+    for (index, image_path) in images.iter().enumerate() {
+        let image = image::open(image_path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", image_path.display(), e))?
+            .to_rgb8();
+        let mut jpeg_data = Vec::new();
+        jpeg::JpegEncoder::new_with_quality(&mut jpeg_data, 100).encode(
+            &image,
+            image.width(),
+            image.height(),
+            image::ExtendedColorType::Rgb8,
+        )?;
+        let jpeg_path = capture_dir.join(format!("frame_{:04}.jpg", index));
+        fs::write(&jpeg_path, &jpeg_data)?;
+        captured_frames.push(CapturedFrame {
+            path: jpeg_path,
+            timestamp_msec: index as i64 * interval_msec,
+            base64: jpeg_base64(&jpeg_data),
+        });
     }
+    Ok(captured_frames)
 }
 
-pub(crate) fn transcode(
-    input_path: &Path,
-    overlay_audio_path: &Path,
+/// Turns the images into a video showing each for `image_msec`, sized like the first one,
+/// with a silent audio track for the commentary to be mixed into.
+pub(crate) fn slideshow(
+    images: &[PathBuf],
+    image_msec: i64,
     output_path: &Path,
-    start_sec: i64,
-    duration_sec: i64,
 ) -> anyhow::Result<()> {
-    let mut input = format::input(input_path)?;
-    let mut output = format::output(&output_path)?;
-    let mut transcoders: HashMap<i32, Box<dyn Transcoder>> = HashMap::new();
-
-    let overlay_audio_filter_spec = if fs::exists(overlay_audio_path)? {
-        format!(
-            "amovie={},atempo=1.25,volume=1.2 [ov]; [in]volume=0.8 [in_vol]; [in_vol][ov] amix=inputs=2:duration=shortest [out]",
-            overlay_audio_path
-                .to_str()
-                .ok_or(anyhow::anyhow!("Invalid comment audio path"))?
-        )
-    } else {
-        "anull".to_owned()
+    let open_image = |image_path: &PathBuf| {
+        image::open(image_path)
+            .map(|image| image.to_rgb8())
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", image_path.display(), e))
     };
+    let first_image = open_image(
+        images
+            .first()
+            .ok_or(anyhow::anyhow!("No images for the slideshow"))?,
+    )?;
+    let mut concatenator = Concatenator::new(
+        first_image.width(),
+        first_image.height(),
+        Rational(25, 1),
+        output_path,
+    )?;
+    concatenator.append_image(&first_image, 0, image_msec)?;
+    for (index, image_path) in images.iter().enumerate().skip(1) {
+        concatenator.append_image(
+            &open_image(image_path)?,
+            index as i64 * image_msec,
+            image_msec,
+        )?;
+    }
+    concatenator.finish()
+}
 
-    println!("Overlay audio filter spec: {}", overlay_audio_filter_spec);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A display matrix as stored in side data, made by FFmpeg itself.
+    fn rotation_matrix(degrees: f64) -> Vec<u8> {
+        let mut matrix = [0i32; 9];
+        unsafe { ffmpeg::ffi::av_display_rotation_set(matrix.as_mut_ptr(), degrees) };
+        matrix
+            .iter()
+            .flat_map(|value| value.to_ne_bytes())
+            .collect()
+    }
 
-    format::context::input::dump(
-        &input,
-        0,
-        Some(input_path.to_str().ok_or(anyhow::anyhow!("Invalid path"))?),
-    );
+    #[test]
+    fn display_rotation_of_ffmpeg_matrices() {
+        for (degrees, rotation) in [
+            (0.0, 0),
+            (90.0, 90),
+            (180.0, 180),
+            (270.0, 270),
+            (-90.0, 270),
+            (-180.0, 180),
+            (-270.0, 90),
+            (89.0, 90),
+        ] {
+            assert_eq!(
+                display_rotation(&rotation_matrix(degrees)),
+                rotation,
+                "{} degrees",
+                degrees
+            );
+        }
+    }
 
-    let start_pos = start_sec.rescale((1, 1), rescale::TIME_BASE);
-    input.seek(start_pos, ..start_pos)?;
+    #[test]
+    fn display_rotation_of_bad_matrices() {
+        assert_eq!(display_rotation(&[]), 0);
+        assert_eq!(display_rotation(&rotation_matrix(90.0)[..32]), 0);
+        assert_eq!(display_rotation(&[0; 36]), 0);
+    }
 
-    let mut stream_mapping = vec![0_i32; input.nb_streams() as _];
-    let mut input_stream_time_base = vec![Rational(0, 0); input.nb_streams() as _];
-    let mut output_stream_time_base = vec![Rational(0, 0); input.nb_streams() as _];
-    let mut output_stream_index = 0;
-    for (ist_index, ist) in input.streams().enumerate() {
-        let ist_medium = ist.parameters().medium();
-        if ist_medium != media::Type::Audio
-            && ist_medium != media::Type::Video
-            && ist_medium != media::Type::Subtitle
-        {
-            stream_mapping[ist_index] = -1;
-            continue;
+    #[test]
+    fn filter_values_are_escaped_for_both_parsers() {
+        assert_eq!(
+            filter_value("output/comment.en.00.mp3"),
+            "output/comment.en.00.mp3"
+        );
+        assert_eq!(
+            filter_value("out/it's [a],b;c:d.mp3"),
+            r"out/it\\\'s \[a\]\,b\;c\\:d.mp3"
+        );
+    }
+
+    #[test]
+    fn clips_are_parsed_without_opening_them() {
+        let clip: Clip = "missing/a@b.mp4@30+15".parse().unwrap();
+        assert_eq!(clip.path, Path::new("missing/a@b.mp4"));
+        assert_eq!(clip.range, Some((30, 15)));
+        let clip: Clip = "missing/a@b.mp4".parse().unwrap();
+        assert_eq!(clip.path, Path::new("missing/a@b.mp4"));
+        assert_eq!(clip.range, None);
+        assert!("missing.mp4@-1+15".parse::<Clip>().is_err());
+        assert!("missing.mp4@30+0".parse::<Clip>().is_err());
+    }
+
+    #[test]
+    fn renditions_are_parsed() {
+        let rendition: Rendition = "720:3000".parse().unwrap();
+        assert_eq!(
+            rendition,
+            Rendition {
+                height: 720,
+                bitrate_kbps: 3000
+            }
+        );
+        for value in [
+            "720",
+            "720:",
+            ":3000",
+            "1:3000",
+            "720:0",
+            "-720:3000",
+            "720:3k",
+        ] {
+            assert!(value.parse::<Rendition>().is_err(), "{}", value);
         }
-        stream_mapping[ist_index] = output_stream_index;
-        input_stream_time_base[ist_index] = ist.time_base();
-        if ist_medium == media::Type::Video {
-            let transcoder = Box::new(VideoTranscoder::new(
-                &ist,
-                &mut output,
-                output_stream_index as _,
-                start_sec,
-            )?);
-            transcoders.insert(ist_index as i32, transcoder);
-        } else if ist_medium == media::Type::Audio {
-            let transcoder = Box::new(AudioTranscoder::new(
-                &ist,
-                &mut output,
-                output_stream_index as _,
-                overlay_audio_filter_spec.as_str(),
-                start_sec,
-            )?);
-            transcoders.insert(ist_index as i32, transcoder);
+    }
+
+    const TEMPO_RANGE: TempoRange = TempoRange { min: 0.5, max: 1.5 };
+
+    #[test]
+    fn length_policy_tempos() {
+        for policy in [
+            LengthPolicy::Range,
+            LengthPolicy::Freeze,
+            LengthPolicy::Continue,
+        ] {
+            assert_eq!(policy.tempo(10000, 12000, TempoRange::UNCHANGED), 1.0);
+            assert_eq!(policy.tempo(10000, 15000, TEMPO_RANGE), 1.5);
+            assert_eq!(policy.tempo(10000, 30000, TEMPO_RANGE), 1.5);
+            assert_eq!(policy.tempo(10000, 2000, TEMPO_RANGE), 0.5);
         }
-        output_stream_index += 1;
+        // Fit speeds the commentary up as far as it takes, but slows it down no more than allowed.
+        assert_eq!(LengthPolicy::Fit.tempo(10000, 30000, TEMPO_RANGE), 3.0);
+        assert_eq!(LengthPolicy::Fit.tempo(10000, 2000, TEMPO_RANGE), 0.5);
+        assert_eq!(LengthPolicy::Fit.tempo(0, 2000, TEMPO_RANGE), 2000.0);
     }
 
-    output.set_metadata(input.metadata().to_owned());
-    format::context::output::dump(
-        &output,
-        0,
-        Some(
-            output_path
-                .to_str()
-                .ok_or(anyhow::anyhow!("Invalid path"))?,
-        ),
-    );
-    output.write_header()?;
+    #[test]
+    fn length_policy_spoken_lengths() {
+        assert_eq!(
+            LengthPolicy::Range.spoken_msec(10000, 30000, TEMPO_RANGE),
+            10000
+        );
+        assert_eq!(
+            LengthPolicy::Freeze.spoken_msec(10000, 30000, TEMPO_RANGE),
+            20000
+        );
+        assert_eq!(
+            LengthPolicy::Continue.spoken_msec(10000, 30000, TEMPO_RANGE),
+            20000
+        );
+        assert_eq!(
+            LengthPolicy::Fit.spoken_msec(10000, 30000, TEMPO_RANGE),
+            10000
+        );
+        for policy in [
+            LengthPolicy::Range,
+            LengthPolicy::Freeze,
+            LengthPolicy::Continue,
+            LengthPolicy::Fit,
+        ] {
+            assert_eq!(policy.spoken_msec(10000, 4000, TEMPO_RANGE), 8000);
+        }
+    }
 
-    for (ost_index, _) in output.streams().enumerate() {
-        output_stream_time_base[ost_index] = output
-            .stream(ost_index)
-            .ok_or(anyhow::anyhow!(Error::StreamNotFound))?
-            .time_base();
+    #[test]
+    fn atempo_filters_are_chained() {
+        assert_eq!(atempo_filter(1.0), "atempo=1.0000");
+        assert_eq!(atempo_filter(2.0), "atempo=2.0000");
+        assert_eq!(atempo_filter(3.0), "atempo=2.0,atempo=1.5000");
+        assert_eq!(atempo_filter(5.0), "atempo=2.0,atempo=2.0,atempo=1.2500");
+        assert_eq!(atempo_filter(0.5), "atempo=0.5000");
+        assert_eq!(atempo_filter(0.3), "atempo=0.5,atempo=0.6000");
+        assert_eq!(atempo_filter(0.2), "atempo=0.5,atempo=0.5,atempo=0.8000");
     }
 
-    for (ist, mut packet) in input.packets() {
-        let ist_index = ist.index();
-        let ost_index = stream_mapping[ist_index];
-        if ost_index < 0 {
-            continue;
+    /// Writes `msec` of silence as 8 kHz mono 16-bit WAV.
+    fn write_silent_wav(path: &Path, msec: u32) -> anyhow::Result<()> {
+        const RATE: u32 = 8000;
+        let data_len = RATE * msec / 1000 * 2;
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        // PCM, one channel, the sample rate, the byte rate, the block size and bits per sample.
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&RATE.to_le_bytes());
+        wav.extend_from_slice(&(RATE * 2).to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_len.to_le_bytes());
+        wav.resize(wav.len() + data_len as usize, 0);
+        fs::write(path, wav)?;
+        Ok(())
+    }
+
+    #[test]
+    fn overlay_plans() {
+        init();
+        let dir = std::env::temp_dir().join(format!("annotai-overlay-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let audio_path = dir.join("comment.wav");
+        write_silent_wav(&audio_path, 3000).unwrap();
+        // Three seconds of commentary in a two second window, and three seconds that were
+        // already fitted to their speed starting two seconds before the end of the range.
+        let tracks = [CommentaryTrack {
+            clips: vec![
+                CommentaryClip {
+                    audio_path: audio_path.clone(),
+                    start_msec: 0,
+                    window_msec: 2000,
+                    speed_fitted: false,
+                },
+                CommentaryClip {
+                    audio_path: audio_path.clone(),
+                    start_msec: 8000,
+                    window_msec: 2000,
+                    speed_fitted: true,
+                },
+            ],
+            language: None,
+        }];
+        let mix = |length_policy, trim_to_windows| Mix {
+            length_policy,
+            tempo_range: TEMPO_RANGE,
+            source_volume: 0.8,
+            commentary_volume: 1.2,
+            trim_to_windows,
+        };
+
+        let plan = OverlayPlan::new(mix(LengthPolicy::Range, false), 10000, &tracks).unwrap();
+        assert_eq!(plan.tempos, [[1.5, 1.0]]);
+        assert!(plan.trim);
+        assert_eq!(plan.amix_duration, "first");
+        assert_eq!(plan.read_msec, 10000);
+        assert_eq!(plan.hold_msec, None);
+        let spec = plan.filter_spec(0, &tracks[0]).unwrap();
+        assert!(spec.contains("atempo=1.5000,atrim=end=2.000,volume=1.2 [ov0]"));
+        assert!(spec.contains("adelay=delays=8000:all=1,volume=1.2 [ov1]"));
+        assert!(spec.contains("[ov0][ov1] amix=inputs=2:duration=longest:normalize=0 [ov]"));
+        assert!(spec.ends_with("[in_vol][ov] amix=inputs=2:duration=first [out]"));
+
+        let plan = OverlayPlan::new(mix(LengthPolicy::Freeze, false), 10000, &tracks).unwrap();
+        assert!(!plan.trim);
+        assert_eq!(plan.amix_duration, "longest");
+        assert_eq!(plan.read_msec, 10000);
+        assert_eq!(plan.hold_msec, Some(11000));
+        let plan = OverlayPlan::new(mix(LengthPolicy::Freeze, true), 10000, &tracks).unwrap();
+        assert_eq!(plan.hold_msec, Some(10000));
+
+        let plan = OverlayPlan::new(mix(LengthPolicy::Continue, false), 10000, &tracks).unwrap();
+        assert_eq!(plan.amix_duration, "first");
+        assert_eq!(plan.read_msec, 11000);
+        assert_eq!(plan.hold_msec, None);
+
+        let plan = OverlayPlan::new(mix(LengthPolicy::Fit, false), 10000, &tracks).unwrap();
+        assert_eq!(plan.tempos, [[1.5, 1.5]]);
+        assert_eq!(plan.read_msec, 10000);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Black picture with a red block in its top left corner.
+    fn test_picture(width: u32, height: u32) -> image::RgbImage {
+        image::RgbImage::from_fn(width, height, |x, y| {
+            if x < width.div_ceil(3) && y < height.div_ceil(3) {
+                image::Rgb([255, 0, 0])
+            } else {
+                image::Rgb([0, 0, 0])
+            }
+        })
+    }
+
+    /// Writes a second of the test picture as uncompressed RGB in a MOV file, with the pixel
+    /// aspect ratio and display matrix rotation given.
+    fn write_test_video(
+        path: &Path,
+        (width, height): (u32, u32),
+        aspect_ratio: Rational,
+        rotation: Option<f64>,
+    ) -> anyhow::Result<()> {
+        init();
+        let mut output = format::output_as(&path, "mov")?;
+        let codec =
+            encoder::find(codec::Id::RAWVIDEO).ok_or(anyhow::anyhow!(Error::EncoderNotFound))?;
+        let mut output_stream = output.add_stream(codec)?;
+        let mut video_encoder = codec::context::Context::new_with_codec(codec)
+            .encoder()
+            .video()?;
+        video_encoder.set_width(width);
+        video_encoder.set_height(height);
+        video_encoder.set_aspect_ratio(aspect_ratio);
+        video_encoder.set_format(format::Pixel::RGB24);
+        video_encoder.set_frame_rate(Some(Rational(10, 1)));
+        video_encoder.set_time_base(Rational(1, 10));
+        output_stream.set_time_base(Rational(1, 10));
+        let mut video_encoder = video_encoder.open()?;
+        output_stream.set_parameters(&video_encoder);
+        if let Some(degrees) = rotation {
+            let matrix = rotation_matrix(degrees);
+            // The muxer takes the rotation from the coded side data of the parameters.
+            unsafe {
+                let parameters = (*output_stream.as_mut_ptr()).codecpar;
+                let side_data = ffmpeg::ffi::av_packet_side_data_new(
+                    &mut (*parameters).coded_side_data,
+                    &mut (*parameters).nb_coded_side_data,
+                    codec::packet::side_data::Type::DisplayMatrix.into(),
+                    matrix.len(),
+                    0,
+                );
+                anyhow::ensure!(!side_data.is_null(), "Failed to add the display matrix");
+                std::ptr::copy_nonoverlapping(matrix.as_ptr(), (*side_data).data, matrix.len());
+            }
         }
-        let end_pts = (start_sec + duration_sec).rescale((1, 1), ist.time_base());
-        let pts = packet.pts().ok_or(anyhow::anyhow!("No pts"))?;
-        if pts >= end_pts {
-            break;
+        output.write_header()?;
+
+        let mut frame = rgb_frame(&test_picture(width, height));
+        for pts in 0..10 {
+            frame.set_pts(Some(pts));
+            video_encoder.send_frame(&frame)?;
+            write_encoded_packets(&mut video_encoder, &mut output, 0)?;
         }
+        video_encoder.send_eof()?;
+        write_encoded_packets(&mut video_encoder, &mut output, 0)?;
+        output.write_trailer()?;
+        Ok(())
+    }
 
-        let ost_time_base = output_stream_time_base[ost_index as usize];
-        match transcoders.get_mut(&(ist_index as i32)) {
-            Some(transcoder) => {
-                transcoder.send_packet_to_decoder(&packet)?;
-                transcoder.receive_and_process_decoded_frames(&mut output, ost_time_base)?;
+    /// Corners in clockwise order from the top left.
+    const CORNERS: [&str; 4] = ["top left", "top right", "bottom right", "bottom left"];
+
+    /// The corner of the image that the red block is in.
+    fn red_corner(image: &image::RgbImage) -> &'static str {
+        let (right, bottom) = (image.width() - 1, image.height() - 1);
+        let redness = |x, y| {
+            let image::Rgb([red, green, blue]) = *image.get_pixel(x, y);
+            red as i32 - (green as i32 + blue as i32) / 2
+        };
+        let positions = [(0, 0), (right, 0), (right, bottom), (0, bottom)];
+        let (corner, _) = positions
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, (x, y))| redness(*x, *y))
+            .unwrap();
+        CORNERS[corner]
+    }
+
+    fn capture_test_video(
+        name: &str,
+        size: (u32, u32),
+        aspect_ratio: Rational,
+        rotation: Option<f64>,
+    ) -> Vec<image::RgbImage> {
+        let dir = std::env::temp_dir().join(format!("annotai-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let video_path = dir.join("video.mov");
+        write_test_video(&video_path, size, aspect_ratio, rotation).unwrap();
+        let frames = capture_base64(
+            &video_path,
+            0,
+            1,
+            500,
+            &dir.join("capture"),
+            &StreamSelection::default(),
+        )
+        .unwrap();
+        let images = frames
+            .iter()
+            .map(|frame| image::open(&frame.path).unwrap().to_rgb8())
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        images
+    }
+
+    #[test]
+    fn captures_odd_sizes() {
+        for (width, height) in [(33, 17), (17, 33), (35, 9)] {
+            let images = capture_test_video(
+                &format!("odd-{}x{}", width, height),
+                (width, height),
+                Rational(1, 1),
+                None,
+            );
+            assert!(!images.is_empty());
+            for image in images {
+                assert_eq!(image.dimensions(), (width, height));
+                assert_eq!(red_corner(&image), "top left");
             }
-            None => {
-                packet.rescale_ts(input_stream_time_base[ist_index], ost_time_base);
-                packet.write_interleaved(&mut output)?;
+        }
+    }
+
+    #[test]
+    fn captures_rotated_upright() {
+        for (degrees, size, corner) in [
+            (90.0, (17, 33), "top right"),
+            (180.0, (33, 17), "bottom right"),
+            (270.0, (17, 33), "bottom left"),
+        ] {
+            let images = capture_test_video(
+                &format!("rotated-{}", degrees),
+                (33, 17),
+                Rational(1, 1),
+                Some(degrees),
+            );
+            assert!(!images.is_empty());
+            for image in images {
+                assert_eq!(image.dimensions(), size, "{} degrees", degrees);
+                assert_eq!(red_corner(&image), corner, "{} degrees", degrees);
             }
         }
     }
 
-    for (ost_index, transcoder) in transcoders.iter_mut() {
-        let ost_time_base = output_stream_time_base[*ost_index as usize];
-        transcoder.send_eof_to_decoder()?;
-        transcoder.receive_and_process_decoded_frames(&mut output, ost_time_base)?;
-        transcoder.flush_filter_graph()?;
-        transcoder.receive_and_process_filtered_frames(&mut output, ost_time_base)?;
-        transcoder.send_eof_to_encoder()?;
-        transcoder.receive_and_process_encoded_packets(&mut output, ost_time_base)?;
+    #[test]
+    fn captures_square_pixels() {
+        let images = capture_test_video("anamorphic", (33, 17), Rational(2, 1), None);
+        assert!(!images.is_empty());
+        for image in images {
+            assert_eq!(image.dimensions(), (66, 17));
+            assert_eq!(red_corner(&image), "top left");
+        }
+
+        let images = capture_test_video("anamorphic-rotated", (33, 17), Rational(3, 2), Some(90.0));
+        assert!(!images.is_empty());
+        for image in images {
+            assert_eq!(image.dimensions(), (17, 49));
+            assert_eq!(red_corner(&image), "top right");
+        }
     }
 
-    output.write_trailer()?;
+    #[test]
+    fn converts_frames_with_their_own_rotation() {
+        init();
+        let mut converter = FrameConverter {
+            scaler: None,
+            tone_mapper: None,
+            stream_rotation: 90,
+        };
+        let mut frame = rgb_frame(&test_picture(33, 17));
+        let image = converter.convert(&frame).unwrap();
+        assert_eq!(image.dimensions(), (17, 33));
+        assert_eq!(red_corner(&image), "top right");
+
+        // A display matrix on the frame overrides the one of the stream.
+        let matrix = rotation_matrix(270.0);
+        let mut side_data = frame
+            .new_side_data(frame::side_data::Type::DisplayMatrix, matrix.len())
+            .unwrap();
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                matrix.as_ptr(),
+                (*side_data.as_mut_ptr()).data,
+                matrix.len(),
+            )
+        };
+        let image = converter.convert(&frame).unwrap();
+        assert_eq!(image.dimensions(), (17, 33));
+        assert_eq!(red_corner(&image), "bottom left");
 
-    Ok(())
+        let mut frame = Video::new(format::Pixel::YUV420P, 33, 17);
+        for plane in 0..3 {
+            frame.data_mut(plane).fill(128);
+        }
+        converter.stream_rotation = 0;
+        let image = converter.convert(&frame).unwrap();
+        assert_eq!(image.dimensions(), (33, 17));
+    }
+
+    #[test]
+    #[ignore = "live capture integration test, run with --ignored"]
+    fn captures_a_file_as_a_live_source() {
+        let dir = std::env::temp_dir().join(format!("annotai-live-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let video_path = dir.join("video.mov");
+        write_test_video(&video_path, (64, 36), Rational(1, 1), None).unwrap();
+        let url = video_path.to_str().unwrap();
+
+        let mut frames = Vec::new();
+        capture_live(url, 500, None, |frame| {
+            frames.push(frame);
+            true
+        })
+        .unwrap();
+        // A second of video seen every half second.
+        assert_eq!(frames.len(), 2);
+        assert!(frames[0].captured_at <= frames[1].captured_at);
+        let jpeg = base64::prelude::BASE64_STANDARD
+            .decode(
+                frames[0]
+                    .base64
+                    .strip_prefix("data:image/jpeg;base64,")
+                    .unwrap(),
+            )
+            .unwrap();
+        let image = image::load_from_memory(&jpeg).unwrap().to_rgb8();
+        assert_eq!(image.dimensions(), (64, 36));
+        assert_eq!(red_corner(&image), "top left");
+
+        let mut frame_count = 0;
+        capture_live(url, 500, None, |_| {
+            frame_count += 1;
+            false
+        })
+        .unwrap();
+        assert_eq!(frame_count, 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,205 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::cache::Cache;
+use crate::manifest::Manifest;
+use crate::pipeline;
+
+#[derive(clap::Args)]
+pub(crate) struct WatchArgs {
+    /// Directory to watch for new videos
+    dir: PathBuf,
+    /// Where to write the outputs, one folder per video named after it [default: <DIR>-annotated]
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+    /// Where to move videos that failed, with a log [default: <DIR>-failed]
+    #[arg(long)]
+    error_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "mp4,mov,mkv,webm,avi,ts,m4v"
+    )]
+    extensions: Vec<String>,
+    #[arg(long, default_value_t = 2)]
+    poll_sec: u64,
+    /// How long the size of a file must stay the same before it counts as finished
+    #[arg(long, default_value_t = 10)]
+    stable_sec: u64,
+    #[command(flatten)]
+    options: pipeline::Options,
+}
+
+struct Candidate {
+    len: u64,
+    modified: SystemTime,
+    since: Instant,
+}
+
+fn sibling_dir(dir: &Path, suffix: &str) -> anyhow::Result<PathBuf> {
+    let dir = dir.canonicalize()?;
+    let (Some(parent), Some(name)) = (dir.parent(), dir.file_name()) else {
+        anyhow::bail!("Cannot put a folder next to {}", dir.display());
+    };
+    Ok(parent.join(format!("{}-{}", name.to_string_lossy(), suffix)))
+}
+
+fn is_done(output_dir: &Path) -> bool {
+    Manifest::load(&output_dir.join("manifest.json")).is_ok_and(|manifest| manifest.mix.is_some())
+}
+
+fn move_file(from: &Path, to: &Path) -> anyhow::Result<()> {
+    // Shares are often on another file system, where rename does not work.
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+/// Moves a video that failed into the error folder, with the error in a log next to it.
+fn set_aside(input_file: &Path, error_root: &Path, error: &anyhow::Error) -> anyhow::Result<()> {
+    let file_name = input_file
+        .file_name()
+        .ok_or(anyhow::anyhow!("Invalid input path"))?;
+    let mut log_name = file_name.to_owned();
+    log_name.push(".log");
+    fs::write(error_root.join(log_name), format!("{:?}\n", error))?;
+    move_file(input_file, &error_root.join(file_name))
+}
+
+pub(crate) async fn watch(args: WatchArgs) -> anyhow::Result<()> {
+    let output_root = match &args.output_dir {
+        Some(output_dir) => output_dir.clone(),
+        None => sibling_dir(&args.dir, "annotated")?,
+    };
+    let error_root = match &args.error_dir {
+        Some(error_dir) => error_dir.clone(),
+        None => sibling_dir(&args.dir, "failed")?,
+    };
+    fs::create_dir_all(&output_root)?;
+    fs::create_dir_all(&error_root)?;
+    let mut options = args.options;
+    // There is nobody at the terminal to answer.
+    options.interactive = false;
+    let options = Arc::new(options);
+    let stable = Duration::from_secs(args.stable_sec);
+    println!(
+        "Watching {} (outputs: {}, failures: {})",
+        args.dir.display(),
+        output_root.display(),
+        error_root.display()
+    );
+
+    let mut candidates: HashMap<PathBuf, Candidate> = HashMap::new();
+    // Failed videos that could not be moved away, skipped until they are.
+    let mut failed: HashSet<PathBuf> = HashSet::new();
+    loop {
+        let mut ready = Vec::new();
+        let mut seen = Vec::new();
+        let entries = match fs::read_dir(&args.dir) {
+            Ok(entries) => entries.collect::<Vec<_>>(),
+            Err(e) => {
+                eprintln!("Failed to read {}: {}", args.dir.display(), e);
+                Vec::new()
+            }
+        };
+        for entry in entries {
+            // Files can go away between listing and looking at them; try again next time.
+            let Ok(entry) = entry else {
+                continue;
+            };
+            let path = entry.path();
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            let matches_extension = path
+                .extension()
+                .map(|extension| extension.to_string_lossy().to_lowercase())
+                .is_some_and(|extension| args.extensions.contains(&extension));
+            if !metadata.is_file() || !matches_extension {
+                continue;
+            }
+            // The whole name, so that a.mp4 and a.mov do not share a folder.
+            let Some(file_name) = path.file_name() else {
+                continue;
+            };
+            let output_dir = output_root.join(file_name);
+            seen.push(path.clone());
+            if failed.contains(&path) || is_done(&output_dir) {
+                continue;
+            }
+
+            let Ok(modified) = metadata.modified() else {
+                continue;
+            };
+            let len = metadata.len();
+            let candidate = candidates.entry(path.clone()).or_insert(Candidate {
+                len,
+                modified,
+                since: Instant::now(),
+            });
+            if candidate.len != len || candidate.modified != modified {
+                *candidate = Candidate {
+                    len,
+                    modified,
+                    since: Instant::now(),
+                };
+            } else if candidate.since.elapsed() >= stable {
+                ready.push((path, output_dir));
+            }
+        }
+        candidates.retain(|path, _| seen.contains(path));
+        failed.retain(|path| seen.contains(path));
+
+        for (input_file, output_dir) in ready {
+            candidates.remove(&input_file);
+            println!("Annotating {}", input_file.display());
+            let cache = if options.no_cache {
+                Cache::disabled()
+            } else {
+                Cache::new(output_root.join(".cache"))
+            };
+            let result = {
+                let (input_file, output_dir, options) =
+                    (input_file.clone(), output_dir.clone(), options.clone());
+                let runtime = tokio::runtime::Handle::current();
+                // The pipeline decodes and encodes synchronously, so keep it off the async
+                // workers, and a panic fails this video rather than the watcher.
+                tokio::task::spawn_blocking(move || {
+                    runtime
+                        .block_on(pipeline::run(
+                            &input_file,
+                            &options,
+                            &output_dir,
+                            &cache,
+                            &|_| {},
+                        ))
+                        .map(|_| ())
+                })
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|result| result)
+            };
+            match result {
+                Ok(()) => println!(
+                    "Annotated {} into {}",
+                    input_file.display(),
+                    output_dir.display()
+                ),
+                Err(e) => {
+                    eprintln!("Failed to annotate {}: {:#}", input_file.display(), e);
+                    if let Err(e) = set_aside(&input_file, &error_root, &e) {
+                        eprintln!("Failed to move {}: {:#}", input_file.display(), e);
+                        failed.insert(input_file);
+                    }
+                }
+            }
+        }
+
+        tokio::time::sleep(Duration::from_secs(args.poll_sec)).await;
+    }
+}